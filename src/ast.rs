@@ -1,27 +1,475 @@
-use crate::tokens::Token;
+use std::cell::Cell;
+use std::fmt::{Display, Formatter, Result};
+use std::rc::Rc;
 
-#[derive(Debug, PartialEq)]
+use crate::tokens::{Span, Token};
+
+#[derive(Debug, PartialEq, Clone)]
 // Expression compiles to a literal
-pub enum Expression {
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExpressionKind {
     Prefix(Token, Box<Expression>),
     Infix(Box<Expression>, Token, Box<Expression>),
     Integer(i64),
     Float(f64),
     String(String),
+    // a string with expressions in braces
+    Interpolation(Vec<Fragment>),
     Boolean(bool),
-    Identifier(String),
-    Variable(String),
-    Assign(String, Box<Expression>),
+    Identifier(Variable),
+    Assign(Variable, Box<Expression>),
+    Call(Box<Expression>, Vec<Expression>),
+    // `receiver.name(args)`
+    Method(Box<Expression>, String, Vec<Expression>),
+    // `receiver?.name(args)`, null without calling anything when the receiver
+    // is null
+    SafeMethod(Box<Expression>, String, Vec<Expression>),
+    Array(Vec<Expression>),
+    // `{key: value, ...}`, keys and values in the order written
+    Map(Vec<(Expression, Expression)>),
+    Index(Box<Expression>, Box<Expression>),
+    // `target[start:end]`, either bound can be left out
+    Slice(Box<Expression>, Option<Box<Expression>>, Option<Box<Expression>>),
+    // `target[index] = value`
+    SetIndex(Box<Expression>, Box<Expression>, Box<Expression>),
+    // `Name { field: value, ... }`, fields in the order written
+    Instance(String, Vec<(String, Expression)>),
+    // `target.field`
+    Field(Box<Expression>, String),
+    // `target?.field`, null when the target is
+    SafeField(Box<Expression>, String),
+    // `target.field = value`
+    SetField(Box<Expression>, String, Box<Expression>),
+    // `Enum::Variant(args)`, a variant without a payload has no arguments
+    Variant(String, String, Vec<Expression>),
+    // `match (subject) { arms }`, the arms tried in order
+    Match(Box<Expression>, Vec<Arm>),
+    Lambda(Rc<Function>),
     Null,
 }
 
+// a piece of an interpolated string
+#[derive(Debug, PartialEq, Clone)]
+pub enum Fragment {
+    Text(String),
+    Expression(Expression),
+}
+
+impl Expression {
+    pub fn new(kind: ExpressionKind, span: Span) -> Expression {
+        Expression { kind, span }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 // Statement compiles to an expression
-pub enum Statement {
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
+    // the `///` comments just before a declaration, one per line
+    pub doc: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum StatementKind {
     Expression(Expression),
     If(Box<Expression>, Box<Statement>),
     IfElse(Box<Expression>, Box<Statement>, Box<Statement>),
     While(Expression, Box<Statement>),
-    Let(String, String, Box<Expression>),
+    Let(Variable, TypeAnnotation, Box<Expression>),
     Block(Vec<Statement>),
     Print(Expression),
+    Function(Variable, Rc<Function>),
+    Return(Option<Expression>),
+    Struct(Rc<Struct>),
+    // the struct name and its methods, each taking `self` first
+    Impl(String, Vec<Rc<Function>>),
+    Enum(Rc<Enum>),
+}
+
+impl Statement {
+    pub fn new(kind: StatementKind, span: Span) -> Statement {
+        Statement {
+            kind,
+            span,
+            doc: Vec::new(),
+        }
+    }
+}
+
+// where a variable lives at runtime: how many scopes out from the one it is
+// used in, and its position within that scope
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Slot {
+    pub depth: usize,
+    pub index: usize,
+}
+
+// a use or declaration of a name, the resolver fills in the slot
+#[derive(Debug, PartialEq, Clone)]
+pub struct Variable {
+    pub name: String,
+    pub slot: Cell<Option<Slot>>,
+}
+
+impl Variable {
+    pub fn new(name: String) -> Variable {
+        Variable {
+            name,
+            slot: Cell::new(None),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Parameter {
+    pub name: String,
+    pub ty: TypeAnnotation,
+    pub span: Span,
+}
+
+// a function declaration or lambda, shared between the AST and the values that
+// call it. Lambdas are called "anonymous"
+#[derive(Debug, PartialEq, Clone)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<Parameter>,
+    pub return_type: TypeAnnotation,
+    pub body: Vec<Statement>,
+    pub span: Span,
+}
+
+// a struct declaration, fields have a name and a type like parameters
+#[derive(Debug, PartialEq, Clone)]
+pub struct Struct {
+    pub name: String,
+    pub fields: Vec<Parameter>,
+    pub span: Span,
+}
+
+// an enum declaration, each variant with the types of its payload
+#[derive(Debug, PartialEq, Clone)]
+pub struct Enum {
+    pub name: String,
+    pub variants: Vec<Variant>,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Variant {
+    pub name: String,
+    pub fields: Vec<TypeAnnotation>,
+    pub span: Span,
+}
+
+// `pattern if guard => body`, the names the pattern binds are in scope in the
+// guard and the body
+#[derive(Debug, PartialEq, Clone)]
+pub struct Arm {
+    pub pattern: Pattern,
+    pub guard: Option<Expression>,
+    pub body: Expression,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Pattern {
+    pub kind: PatternKind,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum PatternKind {
+    // `_`
+    Wildcard,
+    Binding(Variable),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Boolean(bool),
+    Null,
+    // `Enum::Variant(patterns)`
+    Variant(String, String, Vec<Pattern>),
+}
+
+impl Pattern {
+    // the names bound by the pattern and where, left to right
+    pub fn bindings(&self) -> Vec<(&Variable, Span)> {
+        match &self.kind {
+            PatternKind::Binding(v) => vec![(v, self.span)],
+            PatternKind::Variant(_, _, patterns) => patterns.iter().flat_map(|p| p.bindings()).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match &self.kind {
+            PatternKind::Wildcard => write!(f, "_"),
+            PatternKind::Binding(v) => write!(f, "{}", v.name),
+            PatternKind::Integer(i) => write!(f, "{}", i),
+            PatternKind::Float(x) => write!(f, "{:?}", x),
+            PatternKind::String(s) => write!(f, "{:?}", s),
+            PatternKind::Boolean(b) => write!(f, "{}", b),
+            PatternKind::Null => write!(f, "null"),
+            PatternKind::Variant(name, variant, patterns) if patterns.is_empty() => write!(f, "{}::{}", name, variant),
+            PatternKind::Variant(name, variant, patterns) => {
+                let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+                write!(f, "{}::{}({})", name, variant, patterns.join(", "))
+            }
+        }
+    }
+}
+
+// a type as written in the source, e.g. `int64`, `array<string>`, `string?`
+// or `fn(int64) -> bool`
+#[derive(Debug, PartialEq, Clone)]
+pub enum TypeAnnotation {
+    Named(String),
+    Generic(String, Vec<TypeAnnotation>),
+    Function(Vec<TypeAnnotation>, Box<TypeAnnotation>),
+    // `T?`, a T or null
+    Optional(Box<TypeAnnotation>),
+}
+
+impl Display for TypeAnnotation {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            TypeAnnotation::Named(name) => write!(f, "{}", name),
+            TypeAnnotation::Generic(name, args) => {
+                let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+                write!(f, "{}<{}>", name, args.join(", "))
+            }
+            TypeAnnotation::Function(params, ret) => {
+                let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+                write!(f, "fn({}) -> {}", params.join(", "), ret)
+            }
+            TypeAnnotation::Optional(ty) => write!(f, "{}?", ty),
+        }
+    }
+}
+
+// an indented outline of the tree, one node per line
+pub fn outline(stmts: &[Statement]) -> String {
+    let mut out = String::new();
+    for stmt in stmts {
+        outline_statement(stmt, 0, &mut out);
+    }
+    out
+}
+
+fn outline_line(text: &str, depth: usize, out: &mut String) {
+    out.push_str(&"  ".repeat(depth));
+    out.push_str(text);
+    out.push('\n');
+}
+
+fn outline_function(f: &Function, depth: usize, out: &mut String) {
+    let params: Vec<String> = f.params.iter().map(|p| format!("{}: {}", p.name, p.ty)).collect();
+    let text = format!("Function {}({}) -> {}", f.name, params.join(", "), f.return_type);
+    outline_line(&text, depth, out);
+    for stmt in &f.body {
+        outline_statement(stmt, depth + 1, out);
+    }
+}
+
+fn outline_statement(stmt: &Statement, depth: usize, out: &mut String) {
+    for line in &stmt.doc {
+        outline_line(&format!("Doc {:?}", line), depth, out);
+    }
+    match &stmt.kind {
+        StatementKind::Expression(e) => {
+            outline_line("Expression", depth, out);
+            outline_expression(e, depth + 1, out);
+        }
+        StatementKind::If(condition, consequence) => {
+            outline_line("If", depth, out);
+            outline_expression(condition, depth + 1, out);
+            outline_statement(consequence, depth + 1, out);
+        }
+        StatementKind::IfElse(condition, consequence, alternative) => {
+            outline_line("IfElse", depth, out);
+            outline_expression(condition, depth + 1, out);
+            outline_statement(consequence, depth + 1, out);
+            outline_statement(alternative, depth + 1, out);
+        }
+        StatementKind::While(condition, body) => {
+            outline_line("While", depth, out);
+            outline_expression(condition, depth + 1, out);
+            outline_statement(body, depth + 1, out);
+        }
+        StatementKind::Let(v, ty, e) => {
+            outline_line(&format!("Let {}: {}", v.name, ty), depth, out);
+            outline_expression(e, depth + 1, out);
+        }
+        StatementKind::Block(stmts) => {
+            outline_line("Block", depth, out);
+            for stmt in stmts {
+                outline_statement(stmt, depth + 1, out);
+            }
+        }
+        StatementKind::Print(e) => {
+            outline_line("Print", depth, out);
+            outline_expression(e, depth + 1, out);
+        }
+        StatementKind::Function(_, f) => outline_function(f, depth, out),
+        StatementKind::Struct(s) => {
+            outline_line(&format!("Struct {}", s.name), depth, out);
+            for field in &s.fields {
+                outline_line(&format!("Field {}: {}", field.name, field.ty), depth + 1, out);
+            }
+        }
+        StatementKind::Impl(name, methods) => {
+            outline_line(&format!("Impl {}", name), depth, out);
+            for method in methods {
+                outline_function(method, depth + 1, out);
+            }
+        }
+        StatementKind::Enum(e) => {
+            outline_line(&format!("Enum {}", e.name), depth, out);
+            for variant in &e.variants {
+                let fields: Vec<String> = variant.fields.iter().map(|f| f.to_string()).collect();
+                outline_line(&format!("Variant {}({})", variant.name, fields.join(", ")), depth + 1, out);
+            }
+        }
+        StatementKind::Return(value) => {
+            outline_line("Return", depth, out);
+            if let Some(e) = value {
+                outline_expression(e, depth + 1, out);
+            }
+        }
+    }
+}
+
+fn outline_expression(expr: &Expression, depth: usize, out: &mut String) {
+    match &expr.kind {
+        ExpressionKind::Prefix(op, e) => {
+            outline_line(&format!("Prefix {}", op), depth, out);
+            outline_expression(e, depth + 1, out);
+        }
+        ExpressionKind::Infix(left, op, right) => {
+            outline_line(&format!("Infix {}", op), depth, out);
+            outline_expression(left, depth + 1, out);
+            outline_expression(right, depth + 1, out);
+        }
+        ExpressionKind::Integer(i) => outline_line(&format!("Integer {}", i), depth, out),
+        ExpressionKind::Float(f) => outline_line(&format!("Float {}", f), depth, out),
+        ExpressionKind::String(s) => outline_line(&format!("String {:?}", s), depth, out),
+        ExpressionKind::Interpolation(fragments) => {
+            outline_line("Interpolation", depth, out);
+            for fragment in fragments {
+                match fragment {
+                    Fragment::Text(s) => outline_line(&format!("Text {:?}", s), depth + 1, out),
+                    Fragment::Expression(e) => outline_expression(e, depth + 1, out),
+                }
+            }
+        }
+        ExpressionKind::Boolean(b) => outline_line(&format!("Boolean {}", b), depth, out),
+        ExpressionKind::Identifier(v) => outline_line(&format!("Identifier {}", v.name), depth, out),
+        ExpressionKind::Assign(v, e) => {
+            outline_line(&format!("Assign {}", v.name), depth, out);
+            outline_expression(e, depth + 1, out);
+        }
+        ExpressionKind::Call(callee, args) => {
+            outline_line("Call", depth, out);
+            outline_expression(callee, depth + 1, out);
+            for arg in args {
+                outline_expression(arg, depth + 1, out);
+            }
+        }
+        ExpressionKind::Method(receiver, name, args) => {
+            outline_line(&format!("Method {}", name), depth, out);
+            outline_expression(receiver, depth + 1, out);
+            for arg in args {
+                outline_expression(arg, depth + 1, out);
+            }
+        }
+        ExpressionKind::SafeMethod(receiver, name, args) => {
+            outline_line(&format!("SafeMethod {}", name), depth, out);
+            outline_expression(receiver, depth + 1, out);
+            for arg in args {
+                outline_expression(arg, depth + 1, out);
+            }
+        }
+        ExpressionKind::Array(elements) => {
+            outline_line("Array", depth, out);
+            for element in elements {
+                outline_expression(element, depth + 1, out);
+            }
+        }
+        ExpressionKind::Map(entries) => {
+            outline_line("Map", depth, out);
+            for (key, value) in entries {
+                outline_line("Entry", depth + 1, out);
+                outline_expression(key, depth + 2, out);
+                outline_expression(value, depth + 2, out);
+            }
+        }
+        ExpressionKind::Index(target, index) => {
+            outline_line("Index", depth, out);
+            outline_expression(target, depth + 1, out);
+            outline_expression(index, depth + 1, out);
+        }
+        ExpressionKind::Slice(target, start, end) => {
+            outline_line("Slice", depth, out);
+            outline_expression(target, depth + 1, out);
+            for bound in [start, end] {
+                match bound {
+                    Some(e) => outline_expression(e, depth + 1, out),
+                    None => outline_line("Open", depth + 1, out),
+                }
+            }
+        }
+        ExpressionKind::SetIndex(target, index, value) => {
+            outline_line("SetIndex", depth, out);
+            outline_expression(target, depth + 1, out);
+            outline_expression(index, depth + 1, out);
+            outline_expression(value, depth + 1, out);
+        }
+        ExpressionKind::Instance(name, fields) => {
+            outline_line(&format!("Instance {}", name), depth, out);
+            for (field, value) in fields {
+                outline_line(&format!("Field {}", field), depth + 1, out);
+                outline_expression(value, depth + 2, out);
+            }
+        }
+        ExpressionKind::Field(target, field) => {
+            outline_line(&format!("Field {}", field), depth, out);
+            outline_expression(target, depth + 1, out);
+        }
+        ExpressionKind::SafeField(target, field) => {
+            outline_line(&format!("SafeField {}", field), depth, out);
+            outline_expression(target, depth + 1, out);
+        }
+        ExpressionKind::SetField(target, field, value) => {
+            outline_line(&format!("SetField {}", field), depth, out);
+            outline_expression(target, depth + 1, out);
+            outline_expression(value, depth + 1, out);
+        }
+        ExpressionKind::Variant(name, variant, args) => {
+            outline_line(&format!("Variant {}::{}", name, variant), depth, out);
+            for arg in args {
+                outline_expression(arg, depth + 1, out);
+            }
+        }
+        ExpressionKind::Match(subject, arms) => {
+            outline_line("Match", depth, out);
+            outline_expression(subject, depth + 1, out);
+            for arm in arms {
+                outline_line(&format!("Arm {}", arm.pattern), depth + 1, out);
+                if let Some(guard) = &arm.guard {
+                    outline_line("Guard", depth + 2, out);
+                    outline_expression(guard, depth + 3, out);
+                }
+                outline_expression(&arm.body, depth + 2, out);
+            }
+        }
+        ExpressionKind::Lambda(f) => outline_function(f, depth, out),
+        ExpressionKind::Null => outline_line("Null", depth, out),
+    }
 }
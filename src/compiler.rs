@@ -0,0 +1,563 @@
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::eval::Value;
+use crate::tokens::{Span, Token};
+
+// a single vm instruction. Operands index into the chunk they belong to
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Op {
+    Constant(usize),
+    Null,
+    Pop,
+    // the slot and the constant with the variable's name, for the error if
+    // the slot is not defined yet
+    Get(Slot, usize),
+    // the same, leaves the assigned value on the stack
+    Set(Slot, usize),
+    Define(usize),
+    // a variable the resolver never bound, the constant is its name
+    Undefined(usize),
+    Negate,
+    Not,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Equal,
+    NotEqual,
+    Greater,
+    Less,
+    GreaterEqual,
+    LessEqual,
+    // joins that many values from the stack into one string
+    Interpolate(usize),
+    // collects that many values from the stack into an array
+    Array(usize),
+    // pairs that many keys and values from the stack into a map
+    Map(usize),
+    // pops the index or key and the array or map
+    Index,
+    // pops the value, index and array, or value, key and map, and pushes the
+    // value back
+    SetIndex,
+    // pops the end, start and array, a null bound is left open
+    Slice,
+    // the constant with the method name and the argument count, the
+    // receiver sits below the arguments
+    Method(usize, usize),
+    // makes a struct declaration known to the vm
+    Struct(usize),
+    // pops a closure and adds it as a method of the struct named by the
+    // constant
+    DefineMethod(usize),
+    // the constant with the struct name and the field count, pops that many
+    // field names and values
+    Instance(usize, usize),
+    // the constant with the field name, pops the struct
+    GetField(usize),
+    // the constant with the field name, pops the value and the struct and
+    // pushes the value back
+    SetField(usize),
+    // the constants with the enum and variant names and the field count,
+    // pops that many fields
+    Variant(usize, usize, usize),
+    // tests the value on top of the stack against the pattern, leaving it
+    // there. Pushes whether it matched, and on a match defines the names the
+    // pattern binds in the current scope
+    Match(usize),
+    // pops the value no arm matched
+    NoMatch,
+    Jump(usize),
+    // pops the condition
+    JumpIfFalse(usize),
+    // leaves the value on the stack, for `??` and `?.`
+    JumpIfNull(usize),
+    // leaves the value on the stack and jumps if it is false, pops it
+    // otherwise, for `&&`
+    JumpIfFalseOrPop(usize),
+    // the same when it is true, for `||`
+    JumpIfTrueOrPop(usize),
+    EnterScope,
+    ExitScope,
+    Closure(usize),
+    // argument count, the callee sits below the arguments
+    Call(usize),
+    Return,
+    Print,
+}
+
+impl Op {
+    // the operator an arithmetic or comparison instruction came from, for
+    // error messages
+    pub fn token(self) -> Token {
+        match self {
+            Op::Negate | Op::Subtract => Token::Minus,
+            Op::Not => Token::Bang,
+            Op::Add => Token::Plus,
+            Op::Multiply => Token::Times,
+            Op::Divide => Token::Divide,
+            Op::Equal => Token::DoubleEqual,
+            Op::NotEqual => Token::BangEqual,
+            Op::Greater => Token::Greater,
+            Op::Less => Token::Less,
+            Op::GreaterEqual => Token::GreaterEqual,
+            Op::LessEqual => Token::LessEqual,
+            other => panic!("{:?} is not an operator", other),
+        }
+    }
+}
+
+// a statement body the tree-walker names in its error traces, covering
+// instructions [start, end)
+#[derive(Debug, PartialEq, Clone)]
+pub struct Construct {
+    pub name: &'static str,
+    pub span: Span,
+    pub start: usize,
+    pub end: usize,
+}
+
+// the compiled form of the top level or of one function
+#[derive(Debug, PartialEq, Clone)]
+pub struct Chunk {
+    pub name: String,
+    pub code: Vec<Op>,
+    pub constants: Vec<Value>,
+    // line table, the source span of each instruction in `code`
+    pub spans: Vec<Span>,
+    // functions and lambdas created by `Op::Closure`
+    pub functions: Vec<(Rc<Function>, Rc<Chunk>)>,
+    // declarations made known by `Op::Struct`
+    pub structs: Vec<Rc<Struct>>,
+    // patterns tested by `Op::Match`
+    pub patterns: Vec<Pattern>,
+    // innermost first, so the first match for an instruction is the
+    // innermost construct around it
+    pub constructs: Vec<Construct>,
+}
+
+impl Chunk {
+    fn new(name: &str) -> Chunk {
+        Chunk {
+            name: name.to_string(),
+            code: Vec::new(),
+            constants: Vec::new(),
+            spans: Vec::new(),
+            functions: Vec::new(),
+            structs: Vec::new(),
+            patterns: Vec::new(),
+            constructs: Vec::new(),
+        }
+    }
+}
+
+// lowers a resolved and typechecked AST to bytecode. Scopes follow the
+// tree-walker's, except that blocks declaring nothing get no scope at runtime,
+// so slot depths from the resolver are adjusted to skip them
+pub struct Compiler {
+    chunk: Chunk,
+    // every scope the resolver saw, innermost last, and whether it exists at
+    // runtime
+    scopes: Vec<bool>,
+}
+
+impl Compiler {
+    fn new(name: &str, scopes: Vec<bool>) -> Compiler {
+        Compiler {
+            chunk: Chunk::new(name),
+            scopes,
+        }
+    }
+
+    // the runtime depth of a slot the resolver gave
+    fn slot(&self, slot: Slot) -> Slot {
+        let crossed = &self.scopes[self.scopes.len() - slot.depth..];
+        Slot {
+            depth: crossed.iter().filter(|&&real| real).count(),
+            index: slot.index,
+        }
+    }
+
+    fn emit(&mut self, op: Op, span: Span) -> usize {
+        self.chunk.code.push(op);
+        self.chunk.spans.push(span);
+        self.chunk.code.len() - 1
+    }
+
+    fn constant(&mut self, value: Value, span: Span) {
+        self.chunk.constants.push(value);
+        let index = self.chunk.constants.len() - 1;
+        self.emit(Op::Constant(index), span);
+    }
+
+    // point the jump at `at` to the next instruction
+    fn patch(&mut self, at: usize) {
+        let target = self.chunk.code.len();
+        match &mut self.chunk.code[at] {
+            Op::Jump(to) | Op::JumpIfFalse(to) | Op::JumpIfNull(to) | Op::JumpIfFalseOrPop(to) |
+                Op::JumpIfTrueOrPop(to) => *to = target,
+            other => panic!("cannot patch {:?}", other),
+        }
+    }
+
+    // compile `stmt` and record it as a construct for error traces
+    fn construct(&mut self, name: &'static str, span: Span, stmt: &Statement) {
+        let start = self.chunk.code.len();
+        self.statement(stmt);
+        let end = self.chunk.code.len();
+        self.chunk.constructs.push(Construct { name, span, start, end });
+    }
+
+    fn function(&mut self, f: &Rc<Function>, span: Span) {
+        let mut scopes = self.scopes.clone();
+        scopes.push(true);
+        let mut compiler = Compiler::new(&f.name, scopes);
+        for stmt in &f.body {
+            compiler.statement(stmt);
+        }
+        compiler.emit(Op::Null, f.span);
+        compiler.emit(Op::Return, f.span);
+
+        self.chunk.functions.push((f.clone(), Rc::new(compiler.chunk)));
+        let index = self.chunk.functions.len() - 1;
+        self.emit(Op::Closure(index), span);
+    }
+
+    // a string constant for a variable, field, method or struct name
+    fn name(&mut self, name: &str) -> usize {
+        self.chunk.constants.push(Value::String(name.to_string()));
+        self.chunk.constants.len() - 1
+    }
+
+    fn define(&mut self, v: &Variable, span: Span) {
+        match v.slot.get() {
+            Some(slot) => self.emit(Op::Define(slot.index), span),
+            None => self.emit(Op::Pop, span),
+        };
+    }
+
+    fn variable(&mut self, v: &Variable, span: Span, op: fn(Slot, usize) -> Op) {
+        match v.slot.get() {
+            Some(slot) => {
+                let slot = self.slot(slot);
+                let name = self.name(&v.name);
+                self.emit(op(slot, name), span);
+            }
+            None => {
+                let index = self.name(&v.name);
+                self.emit(Op::Undefined(index), span);
+            }
+        }
+    }
+
+    fn statement(&mut self, stmt: &Statement) {
+        let span = stmt.span;
+        match &stmt.kind {
+            StatementKind::Expression(e) => {
+                self.expression(e);
+                self.emit(Op::Pop, span);
+            }
+            StatementKind::Print(e) => {
+                self.expression(e);
+                self.emit(Op::Print, span);
+            }
+            StatementKind::If(condition, consequence) => {
+                self.expression(condition);
+                let skip = self.emit(Op::JumpIfFalse(0), span);
+                self.construct("if statement", span, consequence);
+                self.patch(skip);
+            }
+            StatementKind::IfElse(condition, consequence, alternative) => {
+                self.expression(condition);
+                let skip = self.emit(Op::JumpIfFalse(0), span);
+                self.construct("if statement", span, consequence);
+                let end = self.emit(Op::Jump(0), span);
+                self.patch(skip);
+                self.construct("else branch", alternative.span, alternative);
+                self.patch(end);
+            }
+            StatementKind::While(condition, body) => {
+                let start = self.chunk.code.len();
+                self.expression(condition);
+                let exit = self.emit(Op::JumpIfFalse(0), span);
+                self.construct("while loop", span, body);
+                self.emit(Op::Jump(start), span);
+                self.patch(exit);
+            }
+            StatementKind::Let(v, _ty, e) => {
+                self.expression(e);
+                self.define(v, span);
+            }
+            StatementKind::Block(stmts) => {
+                let declares = stmts.iter().any(|s| {
+                    matches!(s.kind, StatementKind::Let(..) | StatementKind::Function(..))
+                });
+                self.scopes.push(declares);
+                if declares {
+                    self.emit(Op::EnterScope, span);
+                }
+                for stmt in stmts {
+                    self.statement(stmt);
+                }
+                if declares {
+                    self.emit(Op::ExitScope, span);
+                }
+                self.scopes.pop();
+            }
+            StatementKind::Function(v, f) => {
+                self.function(f, span);
+                self.define(v, span);
+            }
+            StatementKind::Return(value) => {
+                match value {
+                    Some(e) => self.expression(e),
+                    None => {
+                        self.emit(Op::Null, span);
+                    }
+                }
+                self.emit(Op::Return, span);
+            }
+            StatementKind::Struct(s) => {
+                self.chunk.structs.push(s.clone());
+                self.emit(Op::Struct(self.chunk.structs.len() - 1), span);
+            }
+            StatementKind::Enum(_) => (),
+            StatementKind::Impl(name, methods) => {
+                let index = self.name(name);
+                for method in methods {
+                    self.function(method, method.span);
+                    self.emit(Op::DefineMethod(index), method.span);
+                }
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression) {
+        let span = expr.span;
+        match &expr.kind {
+            ExpressionKind::Integer(i) => self.constant(Value::Int(*i), span),
+            ExpressionKind::Float(f) => self.constant(Value::Float(*f), span),
+            ExpressionKind::Boolean(b) => self.constant(Value::Bool(*b), span),
+            ExpressionKind::String(s) => self.constant(Value::String(s.clone()), span),
+            ExpressionKind::Interpolation(fragments) => {
+                for fragment in fragments {
+                    match fragment {
+                        Fragment::Text(s) => self.constant(Value::String(s.clone()), span),
+                        Fragment::Expression(e) => self.expression(e),
+                    }
+                }
+                self.emit(Op::Interpolate(fragments.len()), span);
+            }
+            ExpressionKind::Null => {
+                self.emit(Op::Null, span);
+            }
+            ExpressionKind::Identifier(v) => self.variable(v, span, Op::Get),
+            ExpressionKind::Assign(v, e) => {
+                self.expression(e);
+                self.variable(v, span, Op::Set);
+            }
+            ExpressionKind::Prefix(op, e) => {
+                self.expression(e);
+                let op = match op {
+                    Token::Minus => Op::Negate,
+                    Token::Bang => Op::Not,
+                    other => panic!("no prefix operator '{}'", other),
+                };
+                self.emit(op, span);
+            }
+            // a null value is popped for the default, anything else is kept
+            ExpressionKind::Infix(value, Token::DoubleQuestion, default) => {
+                self.expression(value);
+                let null = self.emit(Op::JumpIfNull(0), span);
+                let end = self.emit(Op::Jump(0), span);
+                self.patch(null);
+                self.emit(Op::Pop, span);
+                self.expression(default);
+                self.patch(end);
+            }
+            // the left is the result when it decides it, the right is not run
+            ExpressionKind::Infix(left, op @ (Token::And | Token::Or), right) => {
+                self.expression(left);
+                let jump = if *op == Token::And { Op::JumpIfFalseOrPop(0) } else { Op::JumpIfTrueOrPop(0) };
+                let end = self.emit(jump, span);
+                self.expression(right);
+                self.patch(end);
+            }
+            ExpressionKind::Infix(left, op, right) => {
+                self.expression(left);
+                self.expression(right);
+                let op = match op {
+                    Token::Plus => Op::Add,
+                    Token::Minus => Op::Subtract,
+                    Token::Times => Op::Multiply,
+                    Token::Divide => Op::Divide,
+                    Token::DoubleEqual => Op::Equal,
+                    Token::BangEqual => Op::NotEqual,
+                    Token::Greater => Op::Greater,
+                    Token::Less => Op::Less,
+                    Token::GreaterEqual => Op::GreaterEqual,
+                    Token::LessEqual => Op::LessEqual,
+                    other => panic!("no infix operator '{}'", other),
+                };
+                self.emit(op, span);
+            }
+            ExpressionKind::Call(callee, args) => {
+                self.expression(callee);
+                for arg in args {
+                    self.expression(arg);
+                }
+                self.emit(Op::Call(args.len()), span);
+            }
+            ExpressionKind::Method(receiver, name, args) => {
+                self.expression(receiver);
+                for arg in args {
+                    self.expression(arg);
+                }
+                let index = self.name(name);
+                self.emit(Op::Method(index, args.len()), span);
+            }
+            // a null receiver is left as the result
+            ExpressionKind::SafeMethod(receiver, name, args) => {
+                self.expression(receiver);
+                let null = self.emit(Op::JumpIfNull(0), span);
+                for arg in args {
+                    self.expression(arg);
+                }
+                let index = self.name(name);
+                self.emit(Op::Method(index, args.len()), span);
+                self.patch(null);
+            }
+            ExpressionKind::Array(elements) => {
+                for element in elements {
+                    self.expression(element);
+                }
+                self.emit(Op::Array(elements.len()), span);
+            }
+            ExpressionKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.expression(value);
+                }
+                self.emit(Op::Map(entries.len()), span);
+            }
+            ExpressionKind::Index(target, index) => {
+                self.expression(target);
+                self.expression(index);
+                self.emit(Op::Index, span);
+            }
+            ExpressionKind::Slice(target, start, end) => {
+                self.expression(target);
+                for bound in [start, end] {
+                    match bound {
+                        Some(e) => self.expression(e),
+                        None => {
+                            self.emit(Op::Null, span);
+                        }
+                    }
+                }
+                self.emit(Op::Slice, span);
+            }
+            ExpressionKind::SetIndex(target, index, value) => {
+                self.expression(target);
+                self.expression(index);
+                self.expression(value);
+                self.emit(Op::SetIndex, span);
+            }
+            ExpressionKind::Instance(name, fields) => {
+                for (field, value) in fields {
+                    let index = self.name(field);
+                    self.emit(Op::Constant(index), span);
+                    self.expression(value);
+                }
+                let index = self.name(name);
+                self.emit(Op::Instance(index, fields.len()), span);
+            }
+            ExpressionKind::Field(target, field) => {
+                self.expression(target);
+                let index = self.name(field);
+                self.emit(Op::GetField(index), span);
+            }
+            ExpressionKind::SafeField(target, field) => {
+                self.expression(target);
+                let null = self.emit(Op::JumpIfNull(0), span);
+                let index = self.name(field);
+                self.emit(Op::GetField(index), span);
+                self.patch(null);
+            }
+            ExpressionKind::SetField(target, field, value) => {
+                self.expression(target);
+                self.expression(value);
+                let index = self.name(field);
+                self.emit(Op::SetField(index), span);
+            }
+            ExpressionKind::Variant(name, variant, args) => {
+                for arg in args {
+                    self.expression(arg);
+                }
+                let (name, variant) = (self.name(name), self.name(variant));
+                self.emit(Op::Variant(name, variant, args.len()), span);
+            }
+            ExpressionKind::Match(subject, arms) => self.match_expression(subject, arms, span),
+            ExpressionKind::Lambda(f) => self.function(f, span),
+        }
+    }
+
+    // the subject stays on the stack while the arms are tried and is popped
+    // by the arm that is taken. Only arms that bind names get a scope
+    fn match_expression(&mut self, subject: &Expression, arms: &[Arm], span: Span) {
+        self.expression(subject);
+        let mut ends = Vec::new();
+        for arm in arms {
+            let declares = !arm.pattern.bindings().is_empty();
+            self.scopes.push(declares);
+            if declares {
+                self.emit(Op::EnterScope, arm.pattern.span);
+            }
+            self.chunk.patterns.push(arm.pattern.clone());
+            self.emit(Op::Match(self.chunk.patterns.len() - 1), arm.pattern.span);
+            let mut fails = vec![self.emit(Op::JumpIfFalse(0), arm.pattern.span)];
+            if let Some(guard) = &arm.guard {
+                self.expression(guard);
+                fails.push(self.emit(Op::JumpIfFalse(0), guard.span));
+            }
+            self.emit(Op::Pop, arm.body.span);
+            self.expression(&arm.body);
+            if declares {
+                self.emit(Op::ExitScope, arm.body.span);
+            }
+            ends.push(self.emit(Op::Jump(0), arm.body.span));
+            for fail in fails {
+                self.patch(fail);
+            }
+            if declares {
+                self.emit(Op::ExitScope, arm.pattern.span);
+            }
+            self.scopes.pop();
+        }
+        self.emit(Op::NoMatch, span);
+        for end in ends {
+            self.patch(end);
+        }
+    }
+}
+
+// compile a whole program into the chunk for its top level
+pub fn compile(ast: &[Statement]) -> Chunk {
+    let mut compiler = Compiler::new("script", vec![true]);
+    // top level functions are defined before anything runs, like in the
+    // tree-walker
+    for stmt in ast {
+        if let StatementKind::Function(v, f) = &stmt.kind {
+            compiler.function(f, stmt.span);
+            compiler.define(v, stmt.span);
+        }
+    }
+    for stmt in ast.iter().filter(|stmt| !matches!(stmt.kind, StatementKind::Function(..))) {
+        compiler.statement(stmt);
+    }
+    let end = ast.last().map(|s| s.span.after()).unwrap_or_default();
+    compiler.emit(Op::Null, end);
+    compiler.emit(Op::Return, end);
+    compiler.chunk
+}
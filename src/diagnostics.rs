@@ -0,0 +1,134 @@
+use std::fmt::{Display, Formatter, Result};
+
+use crate::tokens::Span;
+
+// Error codes are grouped by the stage that reports them:
+//
+//   E00xx  lexer        (E0001 unknown character, E0002 unterminated
+//                        block comment, E0003 unterminated string, E0004
+//                        unknown escape, E0005 invalid unicode escape, E0006
+//                        number out of range, E0007 invalid number)
+//   E01xx  parser       (E0100 expected token, E0101 expected expression,
+//                        E0102 expected identifier, E0103 missing initialiser,
+//                        E0104 invalid assignment target, E0105 struct, enum
+//                        or impl not at the top level, E0106 method without
+//                        self, E0107 expected pattern)
+//   E02xx  typechecker  (E0200 type mismatch, E0201 non-boolean condition,
+//                        E0202 invalid operand types, E0203 undefined
+//                        variable, E0204 unknown type, E0205 return outside
+//                        function, E0206 missing return, E0207 wrong argument
+//                        count, E0208 not callable, E0209 not indexable,
+//                        E0210 unknown method, E0211 invalid map key, E0212
+//                        type redeclared, E0213 duplicate field, method or
+//                        variant, E0214 unknown field, E0215 missing field,
+//                        E0216 unknown variant, E0217 wrong pattern field
+//                        count, E0218 non-exhaustive match, E0219
+//                        unreachable match arm, E0220 value may be null)
+//   E04xx  resolver     (E0400 undefined variable, E0401 used before
+//                        declaration, E0402 redeclaration)
+//   E03xx  runtime      (E0300 undefined variable, E0301 division by zero,
+//                        E0302 integer overflow, E0303 invalid operands,
+//                        E0304 not callable, E0305 wrong argument count,
+//                        E0306 stack overflow, E0307 index out of bounds,
+//                        E0308 slice out of bounds, E0309 pop from an empty
+//                        array, E0310 no such method, E0311 key not in
+//                        map, E0312 no such field, E0313 no match arm
+//                        matched)
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub code: &'static str,
+    pub message: String,
+    pub span: Span,
+    // short text printed next to the carets
+    pub label: Option<String>,
+    // extra lines printed under the snippet, e.g. "help: ..."
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: &'static str, message: String, span: Span) -> Diagnostic {
+        Diagnostic {
+            code,
+            message,
+            span,
+            label: None,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn with_label(mut self, label: String) -> Diagnostic {
+        self.label = Some(label);
+        self
+    }
+
+    pub fn with_help(mut self, help: String) -> Diagnostic {
+        self.notes.push(format!("help: {}", help));
+        self
+    }
+
+    pub fn with_note(mut self, note: String) -> Diagnostic {
+        self.notes.push(format!("note: {}", note));
+        self
+    }
+
+    // render rustc style:
+    //
+    //   error[E0100]: Expected ';' after expression.
+    //    --> file.mt:1:8
+    //     |
+    //   1 | print x
+    //     |        ^ expected ';'
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let line_no = self.span.line.to_string();
+        let gutter = " ".repeat(line_no.len());
+        let mut out = format!("error[{}]: {}\n", self.code, self.message);
+        out.push_str(&format!("{}--> {}:{}:{}\n", gutter, file, self.span.line, self.span.column));
+
+        if let Some(line) = source.lines().nth(self.span.line.saturating_sub(1)) {
+            // keep tabs so the carets line up with the source line
+            let padding: String = line
+                .chars()
+                .take(self.span.column - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let rest = line.chars().count().saturating_sub(self.span.column - 1);
+            let width = source
+                .get(self.span.start..self.span.end)
+                .map(|s| s.chars().count())
+                .unwrap_or(1)
+                .min(rest)
+                .max(1);
+            let label = match &self.label {
+                Some(l) => format!(" {}", l),
+                None => String::new(),
+            };
+
+            out.push_str(&format!("{} |\n", gutter));
+            out.push_str(&format!("{} | {}\n", line_no, line));
+            out.push_str(&format!("{} | {}{}{}\n", gutter, padding, "^".repeat(width), label));
+        }
+
+        for note in &self.notes {
+            out.push_str(&format!("{} = {}\n", gutter, note));
+        }
+        out
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "error[{}]: {} at {}:{}",
+            self.code, self.message, self.span.line, self.span.column
+        )
+    }
+}
+
+// print every diagnostic to stderr
+pub fn report(diagnostics: &[Diagnostic], file: &str, source: &str) {
+    for d in diagnostics {
+        eprintln!("{}", d.render(file, source));
+    }
+}
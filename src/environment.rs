@@ -1,50 +1,68 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::ast::Slot;
 use crate::eval::*;
 use anyhow::{Result, anyhow};
 
-#[derive(Debug, Clone, PartialEq, Default)]
+// a single scope, frames are shared so a function call can hang its frame off
+// the scope the function was declared in rather than the caller's. Variables
+// are addressed by the slots the resolver assigned, never by name
+#[derive(Debug, PartialEq, Default)]
 pub struct Environment {
-    enclosing: Option<Box<Environment>>,
-    values: HashMap<String, Value>,
+    enclosing: Option<Rc<RefCell<Environment>>>,
+    values: Vec<Value>,
 }
 
 impl Environment {
     pub fn new() -> Environment {
         Environment {
             enclosing: None,
-            values: HashMap::new(),
+            values: Vec::new(),
         }
     }
 
-    pub fn new_from(enclosing: Environment) -> Environment {
+    pub fn new_from(enclosing: Rc<RefCell<Environment>>) -> Environment {
         Environment {
-            enclosing: Some(Box::new(enclosing)),
-            values: HashMap::default(),
+            enclosing: Some(enclosing),
+            values: Vec::new(),
         }
     }
 
-    pub fn define(&mut self, name: String, value: Value) {
-        self.values.insert(name, value);
+    pub fn enclosing(&self) -> Option<Rc<RefCell<Environment>>> {
+        self.enclosing.clone()
     }
 
-    pub fn get(&self, name: &str) -> Option<Value> {
-        if let Some(value) = self.values.get(name) {
-            Some(value.clone())
+    // set slot `index` of this scope, slots skipped over are left null
+    pub fn define(&mut self, index: usize, value: Value) {
+        if index >= self.values.len() {
+            self.values.resize(index + 1, Value::Null);
+        }
+        self.values[index] = value;
+    }
+
+    pub fn get(&self, slot: Slot) -> Option<Value> {
+        if slot.depth == 0 {
+            self.values.get(slot.index).cloned()
         } else if let Some(enclosing) = &self.enclosing {
-            enclosing.get(name)
+            enclosing.borrow().get(Slot { depth: slot.depth - 1, ..slot })
         } else {
             None
         }
     }
 
-    pub fn assign(&mut self, name: &str, value: Value) -> Result<()> {
-        if self.values.contains_key(name) {
-            self.values.insert(name.to_string(), value);
-            Ok(())
-        } else if let Some(enclosing) = &mut self.enclosing {
-            enclosing.assign(name, value)
+    pub fn assign(&mut self, slot: Slot, value: Value) -> Result<()> {
+        if slot.depth == 0 {
+            match self.values.get_mut(slot.index) {
+                Some(v) => {
+                    *v = value;
+                    Ok(())
+                }
+                None => Err(anyhow!("Undefined slot {}", slot.index)),
+            }
+        } else if let Some(enclosing) = &self.enclosing {
+            enclosing.borrow_mut().assign(Slot { depth: slot.depth - 1, ..slot }, value)
         } else {
-            Err(anyhow!("Undefined variable '{}'", name))
+            Err(anyhow!("Undefined slot {}", slot.index))
         }
     }
 }
@@ -1,18 +1,108 @@
-use std::fmt::{Display, Formatter, Result};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::rc::Rc;
+
+use indexmap::IndexMap;
 
 use crate::ast::*;
+use crate::compiler::Chunk;
+use crate::diagnostics::Diagnostic;
 use crate::tokens::*;
 use crate::environment::*;
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Clone)]
 pub enum Value {
     Int(i64),
     Float(f64),
     Bool(bool),
     String(String),
+    // shared, so every copy of an array sees pushes and assignments to it
+    Array(Rc<RefCell<Vec<Value>>>),
+    // kept in insertion order, so printing and `keys()` are deterministic
+    Map(Rc<RefCell<IndexMap<Key, Value>>>),
+    // shared like arrays, so a method can change the fields of `self`
+    Struct(Rc<RefCell<Instance>>),
+    // immutable, so copies can share the payload
+    Enum(Rc<EnumValue>),
+    Function(Closure),
     Null,
 }
 
+// a value of a struct type, its fields in the order the struct declares them
+#[derive(Debug, PartialEq, Clone)]
+pub struct Instance {
+    pub name: String,
+    pub fields: Vec<(String, Value)>,
+}
+
+// a value of an enum type: the enum, the variant and its payload
+#[derive(Debug, PartialEq, Clone)]
+pub struct EnumValue {
+    pub name: String,
+    pub variant: String,
+    pub fields: Vec<Value>,
+}
+
+// a struct declaration and the methods impl blocks gave it. The evaluator and
+// the vm each keep one per struct declaration they have run
+#[derive(Debug, Clone)]
+pub struct StructType {
+    pub declaration: Rc<Struct>,
+    pub methods: HashMap<String, Closure>,
+}
+
+impl StructType {
+    pub fn new(declaration: Rc<Struct>) -> StructType {
+        StructType {
+            declaration,
+            methods: HashMap::new(),
+        }
+    }
+}
+
+// a value that can be a map key
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Key {
+    Int(i64),
+    Bool(bool),
+    String(String),
+}
+
+impl Key {
+    pub fn value(&self) -> Value {
+        match self {
+            Key::Int(i) => Value::Int(*i),
+            Key::Bool(b) => Value::Bool(*b),
+            Key::String(s) => Value::String(s.clone()),
+        }
+    }
+}
+
+// a function together with the scope it was created in. The scope is shared,
+// not copied, so the function sees later assignments to captured variables
+#[derive(Clone)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub env: Rc<RefCell<Environment>>,
+    // the compiled body, only set for closures made by the vm
+    pub chunk: Option<Rc<Chunk>>,
+}
+
+// closures are compared and printed by identity, their scope can contain the
+// closure itself
+impl PartialEq for Closure {
+    fn eq(&self, other: &Closure) -> bool {
+        Rc::ptr_eq(&self.function, &other.function) && Rc::ptr_eq(&self.env, &other.env)
+    }
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Closure({})", self.function.name)
+    }
+}
+
 impl Value {
     pub fn is_truthy(&self) -> bool {
         match self {
@@ -21,194 +111,1060 @@ impl Value {
             _ => true,
         }
     }
+
+    // the mt name of this value's type, for error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int64",
+            Value::Float(_) => "float64",
+            Value::Bool(_) => "bool",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Map(_) => "map",
+            Value::Struct(_) => "struct",
+            Value::Enum(_) => "enum",
+            Value::Function(_) => "function",
+            Value::Null => "null",
+        }
+    }
+}
+
+// a string as an mt literal, with the escapes the lexer reads back
+fn quote(s: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' | '{' | '}' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            '\0' => quoted.push_str("\\0"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// how a value is written inside an array or map: as mt source, so strings
+// are quoted and whole floats keep their point. `["a, b"]` and `["a", "b"]`
+// print differently and a printed map can be pasted back into a program
+struct Literal<'a>(&'a Value);
+
+impl Display for Literal<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        show(f, self.0, true, &mut Vec::new())
+    }
 }
 
 impl Display for Value {
-    fn fmt(&self, f: &mut Formatter) -> Result {
-        match self {
-            Value::Int(i) => write!(f, "{}", i),
-            Value::Float(fl) => write!(f, "{}", fl),
-            Value::Bool(b) => write!(f, "{}", b),
-            Value::String(s) => write!(f, "{}", s),
-            Value::Null => write!(f, "null"),
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        show(f, self, false, &mut Vec::new())
+    }
+}
+
+// where a struct value lives, which tells apart values that share it
+type Address = *const RefCell<Instance>;
+
+// write `value`, as a literal if it is inside another value. `path` holds the
+// structs it is inside, a struct that contains itself is written as
+// `Name {...}` where it comes round again
+fn show(f: &mut Formatter, value: &Value, literal: bool, path: &mut Vec<Address>) -> fmt::Result {
+    match value {
+        Value::Int(i) => write!(f, "{}", i),
+        Value::Float(fl) if literal => write!(f, "{:?}", fl),
+        Value::Float(fl) => write!(f, "{}", fl),
+        Value::Bool(b) => write!(f, "{}", b),
+        Value::String(s) if literal => write!(f, "{}", quote(s)),
+        Value::String(s) => write!(f, "{}", s),
+        Value::Array(elements) => {
+            write!(f, "[")?;
+            for (i, element) in elements.borrow().iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                show(f, element, true, path)?;
+            }
+            write!(f, "]")
         }
+        Value::Map(entries) => {
+            write!(f, "{{")?;
+            for (i, (k, v)) in entries.borrow().iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                show(f, &k.value(), true, path)?;
+                write!(f, ": ")?;
+                show(f, v, true, path)?;
+            }
+            write!(f, "}}")
+        }
+        Value::Struct(instance) if path.contains(&Rc::as_ptr(instance)) => {
+            write!(f, "{} {{...}}", instance.borrow().name)
+        }
+        Value::Struct(instance) => {
+            let fields = &instance.borrow().fields;
+            let name = &instance.borrow().name;
+            if fields.is_empty() {
+                return write!(f, "{} {{}}", name);
+            }
+            path.push(Rc::as_ptr(instance));
+            write!(f, "{} {{ ", name)?;
+            for (i, (field, v)) in fields.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}: ", field)?;
+                show(f, v, true, path)?;
+            }
+            path.pop();
+            write!(f, " }}")
+        }
+        Value::Enum(value) if value.fields.is_empty() => write!(f, "{}::{}", value.name, value.variant),
+        Value::Enum(value) => {
+            write!(f, "{}::{}(", value.name, value.variant)?;
+            for (i, v) in value.fields.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                show(f, v, true, path)?;
+            }
+            write!(f, ")")
+        }
+        Value::Function(closure) => write!(f, "<fn {}>", closure.function.name),
+        Value::Null => write!(f, "null"),
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        equal(self, other)
     }
 }
 
+// `==` on values: by content, except for functions. The evaluator and the vm
+// both use it
+pub fn equal(left: &Value, right: &Value) -> bool {
+    equal_in(left, right, &mut HashSet::new())
+}
+
+// structs can contain themselves, so `seen` holds the pairs of structs
+// already being compared, which count as equal if they come round again. Any
+// difference found on the way makes the whole comparison false
+fn equal_in(left: &Value, right: &Value, seen: &mut HashSet<(Address, Address)>) -> bool {
+    match (left, right) {
+        (Value::Int(l), Value::Int(r)) => l == r,
+        (Value::Float(l), Value::Float(r)) => l == r,
+        (Value::Bool(l), Value::Bool(r)) => l == r,
+        (Value::String(l), Value::String(r)) => l == r,
+        (Value::Array(l), Value::Array(r)) => {
+            if Rc::ptr_eq(l, r) {
+                return true;
+            }
+            let (l, r) = (l.borrow(), r.borrow());
+            l.len() == r.len() && l.iter().zip(r.iter()).all(|(a, b)| equal_in(a, b, seen))
+        }
+        // maps with the same entries are equal whatever their order
+        (Value::Map(l), Value::Map(r)) => {
+            if Rc::ptr_eq(l, r) {
+                return true;
+            }
+            let (l, r) = (l.borrow(), r.borrow());
+            l.len() == r.len() && l.iter().all(|(k, a)| r.get(k).is_some_and(|b| equal_in(a, b, seen)))
+        }
+        (Value::Struct(l), Value::Struct(r)) => {
+            if Rc::ptr_eq(l, r) || !seen.insert((Rc::as_ptr(l), Rc::as_ptr(r))) {
+                return true;
+            }
+            let (l, r) = (l.borrow(), r.borrow());
+            l.name == r.name
+                && l.fields.len() == r.fields.len()
+                && l.fields.iter().zip(r.fields.iter()).all(|((n1, a), (n2, b))| n1 == n2 && equal_in(a, b, seen))
+        }
+        (Value::Enum(l), Value::Enum(r)) => {
+            Rc::ptr_eq(l, r)
+                || (l.name == r.name
+                    && l.variant == r.variant
+                    && l.fields.len() == r.fields.len()
+                    && l.fields.iter().zip(r.fields.iter()).all(|(a, b)| equal_in(a, b, seen)))
+        }
+        (Value::Null, Value::Null) => true,
+        _ => false,
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RuntimeErrorKind {
+    UndefinedVariable(String),
+    DivisionByZero,
+    Overflow(Token),
+    // operator and the type names of its operands
+    InvalidOperands(Token, Vec<&'static str>),
+    NotCallable(&'static str),
+    // expected and given argument counts
+    WrongArgumentCount(usize, usize),
+    // the limit on nested calls
+    StackOverflow(usize),
+    // the index and the length of the array
+    IndexOutOfBounds(i64, usize),
+    // the bounds and the length of the array
+    SliceOutOfBounds(i64, i64, usize),
+    EmptyArray,
+    // the key, written as a literal
+    KeyNotFound(String),
+    // the type name of the receiver and the method
+    NoMethod(&'static str, String),
+    NoField(&'static str, String),
+    // the value matched, written as a literal
+    NoMatch(String),
+}
+
+// a construct that was being evaluated when an error happened
+#[derive(Debug, PartialEq, Clone)]
+pub struct Frame {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub span: Span,
+    // innermost construct first
+    pub trace: Vec<Frame>,
+}
+
+impl RuntimeError {
+    pub fn new(kind: RuntimeErrorKind, span: Span) -> RuntimeError {
+        RuntimeError {
+            kind,
+            span,
+            trace: Vec::new(),
+        }
+    }
+
+    // record that the error passed through `name` on its way out
+    pub fn within(mut self, name: &str, span: Span) -> RuntimeError {
+        self.trace.push(Frame {
+            name: name.to_string(),
+            span,
+        });
+        self
+    }
+
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let d = match &self.kind {
+            RuntimeErrorKind::UndefinedVariable(name) => {
+                Diagnostic::error("E0300", format!("Undefined variable '{}'", name), self.span)
+            }
+            RuntimeErrorKind::DivisionByZero => {
+                Diagnostic::error("E0301", "Division by zero".to_string(), self.span)
+            }
+            RuntimeErrorKind::Overflow(op) => {
+                Diagnostic::error("E0302", format!("Integer overflow in '{}'", op), self.span)
+            }
+            RuntimeErrorKind::InvalidOperands(op, types) => Diagnostic::error(
+                "E0303",
+                format!("Invalid operands for '{}'", op),
+                self.span,
+            )
+            .with_label(format!("found {}", types.join(" and "))),
+            RuntimeErrorKind::NotCallable(ty) => {
+                Diagnostic::error("E0304", format!("Cannot call a value of type {}", ty), self.span)
+            }
+            RuntimeErrorKind::WrongArgumentCount(expected, found) => Diagnostic::error(
+                "E0305",
+                format!("Expected {} arguments but got {}", expected, found),
+                self.span,
+            ),
+            RuntimeErrorKind::StackOverflow(limit) => Diagnostic::error(
+                "E0306",
+                format!("Stack overflow, calls nested deeper than {}", limit),
+                self.span,
+            ),
+            RuntimeErrorKind::IndexOutOfBounds(index, len) => Diagnostic::error(
+                "E0307",
+                format!("Index {} out of bounds for array of length {}", index, len),
+                self.span,
+            ),
+            RuntimeErrorKind::SliceOutOfBounds(start, end, len) => Diagnostic::error(
+                "E0308",
+                format!("Slice {}:{} out of bounds for array of length {}", start, end, len),
+                self.span,
+            ),
+            RuntimeErrorKind::EmptyArray => {
+                Diagnostic::error("E0309", "Cannot pop from an empty array".to_string(), self.span)
+            }
+            RuntimeErrorKind::KeyNotFound(key) => {
+                Diagnostic::error("E0311", format!("Key {} not found in map", key), self.span)
+            }
+            RuntimeErrorKind::NoMethod(ty, name) => Diagnostic::error(
+                "E0310",
+                format!("No method '{}' on a value of type {}", name, ty),
+                self.span,
+            ),
+            RuntimeErrorKind::NoField(ty, name) => Diagnostic::error(
+                "E0312",
+                format!("No field '{}' on a value of type {}", name, ty),
+                self.span,
+            ),
+            RuntimeErrorKind::NoMatch(value) => {
+                Diagnostic::error("E0313", format!("No match arm matches {}", value), self.span)
+            }
+        };
+        // runs of the same frame (deep recursion) are printed once with a count
+        let mut d = d;
+        let mut i = 0;
+        while i < self.trace.len() {
+            let frame = &self.trace[i];
+            let repeats = self.trace[i..].iter().take_while(|f| *f == frame).count();
+            let mut note = format!("in {} at {}:{}", frame.name, frame.span.line, frame.span.column);
+            if repeats > 1 {
+                note.push_str(&format!(" ({} times)", repeats));
+            }
+            d = d.with_note(note);
+            i += repeats;
+        }
+        d
+    }
+}
+
+type Result<T> = std::result::Result<T, RuntimeError>;
+
+// the operations on arrays, maps and structs, shared with the vm so both
+// fail the same way
+
+fn position(index: i64, len: usize) -> std::result::Result<usize, RuntimeErrorKind> {
+    usize::try_from(index)
+        .ok()
+        .filter(|&i| i < len)
+        .ok_or(RuntimeErrorKind::IndexOutOfBounds(index, len))
+}
+
+fn not_indexable(target: &Value, index: &Value) -> RuntimeErrorKind {
+    RuntimeErrorKind::InvalidOperands(Token::LeftBracket, vec![target.type_name(), index.type_name()])
+}
+
+// `value` as a key of the map `map`
+fn key(map: &Value, value: &Value) -> std::result::Result<Key, RuntimeErrorKind> {
+    match value {
+        Value::Int(i) => Ok(Key::Int(*i)),
+        Value::Bool(b) => Ok(Key::Bool(*b)),
+        Value::String(s) => Ok(Key::String(s.clone())),
+        _ => Err(not_indexable(map, value)),
+    }
+}
+
+fn key_not_found(key: &Key) -> RuntimeErrorKind {
+    RuntimeErrorKind::KeyNotFound(Literal(&key.value()).to_string())
+}
+
+pub fn index(target: &Value, index: &Value) -> std::result::Result<Value, RuntimeErrorKind> {
+    match (target, index) {
+        (Value::Array(elements), Value::Int(i)) => {
+            let elements = elements.borrow();
+            Ok(elements[position(*i, elements.len())?].clone())
+        }
+        (Value::Map(entries), _) => {
+            let key = key(target, index)?;
+            entries.borrow().get(&key).cloned().ok_or_else(|| key_not_found(&key))
+        }
+        _ => Err(not_indexable(target, index)),
+    }
+}
+
+pub fn set_index(target: &Value, index: &Value, value: Value) -> std::result::Result<(), RuntimeErrorKind> {
+    match (target, index) {
+        (Value::Array(elements), Value::Int(i)) => {
+            let mut elements = elements.borrow_mut();
+            let i = position(*i, elements.len())?;
+            elements[i] = value;
+            Ok(())
+        }
+        // a new key goes at the end, an existing one keeps its place
+        (Value::Map(entries), _) => {
+            entries.borrow_mut().insert(key(target, index)?, value);
+            Ok(())
+        }
+        _ => Err(not_indexable(target, index)),
+    }
+}
+
+// a copy of `target[start:end]`, a null bound is the start or end of the array
+pub fn slice(target: &Value, start: &Value, end: &Value) -> std::result::Result<Value, RuntimeErrorKind> {
+    let elements = match target {
+        Value::Array(elements) => elements.borrow(),
+        _ => return Err(not_indexable(target, start)),
+    };
+    let len = elements.len();
+    let bound = |value: &Value, default: usize| match value {
+        Value::Int(i) => Ok(*i),
+        Value::Null => Ok(default as i64),
+        other => Err(not_indexable(target, other)),
+    };
+    let (start, end) = (bound(start, 0)?, bound(end, len)?);
+    if start < 0 || start > end || end > len as i64 {
+        return Err(RuntimeErrorKind::SliceOutOfBounds(start, end, len));
+    }
+    let slice = elements[start as usize..end as usize].to_vec();
+    Ok(Value::Array(Rc::new(RefCell::new(slice))))
+}
+
+// a value of the struct `name` from its fields in any order. Only a program
+// that skipped the typechecker can name a struct that was never declared
+pub fn instance(
+    structs: &HashMap<String, StructType>,
+    name: &str,
+    mut fields: Vec<(String, Value)>,
+) -> std::result::Result<Value, RuntimeErrorKind> {
+    let ty = structs.get(name).ok_or_else(|| RuntimeErrorKind::UndefinedVariable(name.to_string()))?;
+    let fields = ty
+        .declaration
+        .fields
+        .iter()
+        .map(|field| {
+            let value = match fields.iter().position(|(f, _)| *f == field.name) {
+                Some(i) => fields.swap_remove(i).1,
+                None => Value::Null,
+            };
+            (field.name.clone(), value)
+        })
+        .collect();
+    let instance = Instance {
+        name: name.to_string(),
+        fields,
+    };
+    Ok(Value::Struct(Rc::new(RefCell::new(instance))))
+}
+
+pub fn field(target: &Value, name: &str) -> std::result::Result<Value, RuntimeErrorKind> {
+    if let Value::Struct(instance) = target {
+        if let Some((_, value)) = instance.borrow().fields.iter().find(|(f, _)| f == name) {
+            return Ok(value.clone());
+        }
+    }
+    Err(RuntimeErrorKind::NoField(target.type_name(), name.to_string()))
+}
+
+pub fn set_field(target: &Value, name: &str, value: Value) -> std::result::Result<(), RuntimeErrorKind> {
+    if let Value::Struct(instance) = target {
+        if let Some((_, old)) = instance.borrow_mut().fields.iter_mut().find(|(f, _)| f == name) {
+            *old = value;
+            return Ok(());
+        }
+    }
+    Err(RuntimeErrorKind::NoField(target.type_name(), name.to_string()))
+}
+
+// the method `name` an impl block gave the struct `receiver`
+pub fn struct_method(structs: &HashMap<String, StructType>, receiver: &Value, name: &str) -> Option<Closure> {
+    match receiver {
+        Value::Struct(instance) => structs.get(&instance.borrow().name)?.methods.get(name).cloned(),
+        _ => None,
+    }
+}
+
+pub fn call_method(receiver: &Value, name: &str, args: Vec<Value>) -> std::result::Result<Value, RuntimeErrorKind> {
+    let arity = |expected: usize| {
+        if args.len() == expected {
+            Ok(())
+        } else {
+            Err(RuntimeErrorKind::WrongArgumentCount(expected, args.len()))
+        }
+    };
+    match (receiver, name) {
+        (Value::Array(elements), "len") => {
+            arity(0)?;
+            Ok(Value::Int(elements.borrow().len() as i64))
+        }
+        (Value::Array(elements), "push") => {
+            arity(1)?;
+            elements.borrow_mut().extend(args);
+            Ok(Value::Null)
+        }
+        (Value::Array(elements), "pop") => {
+            arity(0)?;
+            elements.borrow_mut().pop().ok_or(RuntimeErrorKind::EmptyArray)
+        }
+        (Value::Map(entries), "len") => {
+            arity(0)?;
+            Ok(Value::Int(entries.borrow().len() as i64))
+        }
+        (Value::Map(entries), "contains") => {
+            arity(1)?;
+            Ok(Value::Bool(entries.borrow().contains_key(&key(receiver, &args[0])?)))
+        }
+        (Value::Map(entries), "insert") => {
+            arity(2)?;
+            let mut args = args.into_iter();
+            let (k, v) = (args.next().unwrap(), args.next().unwrap());
+            entries.borrow_mut().insert(key(receiver, &k)?, v);
+            Ok(Value::Null)
+        }
+        // the entries after the removed one keep their order
+        (Value::Map(entries), "remove") => {
+            arity(1)?;
+            let key = key(receiver, &args[0])?;
+            entries.borrow_mut().shift_remove(&key).ok_or_else(|| key_not_found(&key))
+        }
+        (Value::Map(entries), "keys") => {
+            arity(0)?;
+            let keys = entries.borrow().keys().map(Key::value).collect();
+            Ok(Value::Array(Rc::new(RefCell::new(keys))))
+        }
+        (Value::Map(entries), "values") => {
+            arity(0)?;
+            let values = entries.borrow().values().cloned().collect();
+            Ok(Value::Array(Rc::new(RefCell::new(values))))
+        }
+        _ => Err(RuntimeErrorKind::NoMethod(receiver.type_name(), name.to_string())),
+    }
+}
+
+// a variant value from its evaluated payload
+pub fn variant(name: &str, variant: &str, fields: Vec<Value>) -> Value {
+    Value::Enum(Rc::new(EnumValue {
+        name: name.to_string(),
+        variant: variant.to_string(),
+        fields,
+    }))
+}
+
+// does `value` match `pattern`, collecting the slot and value of each name it
+// binds. Literals match the values `==` says they equal
+pub fn matches(pattern: &Pattern, value: &Value, bindings: &mut Vec<(usize, Value)>) -> bool {
+    match (&pattern.kind, value) {
+        (PatternKind::Wildcard, _) => true,
+        (PatternKind::Binding(v), _) => {
+            if let Some(slot) = v.slot.get() {
+                bindings.push((slot.index, value.clone()));
+            }
+            true
+        }
+        (PatternKind::Integer(p), Value::Int(i)) => p == i,
+        (PatternKind::Float(p), Value::Float(f)) => p == f,
+        (PatternKind::String(p), Value::String(s)) => p == s,
+        (PatternKind::Boolean(p), Value::Bool(b)) => p == b,
+        (PatternKind::Null, Value::Null) => true,
+        (PatternKind::Variant(name, variant, patterns), Value::Enum(value)) => {
+            value.name == *name
+                && value.variant == *variant
+                && patterns.len() == value.fields.len()
+                && patterns.iter().zip(&value.fields).all(|(p, v)| matches(p, v, bindings))
+        }
+        _ => false,
+    }
+}
+
+pub fn no_match(value: &Value) -> RuntimeErrorKind {
+    RuntimeErrorKind::NoMatch(Literal(value).to_string())
+}
+
+// deeper recursion than this is reported instead of overflowing the Rust
+// stack, which main.rs makes large enough for it
+pub const MAX_CALL_DEPTH: usize = 10_000;
+
+// how control leaves a statement
+enum Flow {
+    Normal(Value),
+    Return(Value),
+}
+
 pub struct Evaluator {
-    env: Environment,
+    env: Rc<RefCell<Environment>>,
+    depth: usize,
+    structs: HashMap<String, StructType>,
 }
 
 impl Evaluator {
     pub fn new() -> Evaluator {
         Evaluator {
-            env: Environment::new(),
-        }
-    }
-
-    fn eval_expression(&mut self, expr: &Expression) -> Value {
-        match expr {
-            Expression::Integer(i) => Value::Int(*i),
-            Expression::Float(f) => Value::Float(*f),
-            Expression::Boolean(b) => Value::Bool(*b),
-            Expression::String(s) => Value::String(s.clone()),
-            Expression::Identifier(name) => self.env.get(name).unwrap(),
-            Expression::Variable(name) => self.env.get(name).unwrap(),
-            Expression::Assign(name, expr) => {
-                let e = self.eval_expression(expr);
-                println!("{} = {:?}", name, e);
-                self.env.assign(name, e.clone()).unwrap();
-                e
-            }
-            Expression::Prefix(t, e) => {
-                let right = self.eval_expression(&e);
-                match t {
-                    Token::Minus => match right {
-                        Value::Int(i) => Value::Int(-i),
-                        Value::Float(fl) => Value::Float(-fl),
-                        _ => panic!("Invalid operand for prefix '-'"),
-                    },
-                    Token::Bang => match right {
-                        Value::Bool(b) => Value::Bool(!b),
-                        _ => panic!("Invalid operand for prefix '!'"),
-                    },
-                    _ => panic!("Invalid prefix operator"),
+            env: Rc::new(RefCell::new(Environment::new())),
+            depth: 0,
+            structs: HashMap::new(),
+        }
+    }
+
+    // the value in slot `index` of the global scope
+    pub fn global(&self, index: usize) -> Option<Value> {
+        self.env.borrow().get(Slot { depth: 0, index })
+    }
+
+    fn closure(&self, f: &Rc<Function>) -> Value {
+        Value::Function(Closure {
+            function: f.clone(),
+            env: self.env.clone(),
+            chunk: None,
+        })
+    }
+
+    fn invalid(op: &Token, values: &[&Value], span: Span) -> RuntimeError {
+        let types = values.iter().map(|v| v.type_name()).collect();
+        RuntimeError::new(RuntimeErrorKind::InvalidOperands(op.clone(), types), span)
+    }
+
+    // only reachable when the resolver was skipped or reported errors
+    fn undefined(v: &Variable, span: Span) -> RuntimeError {
+        RuntimeError::new(RuntimeErrorKind::UndefinedVariable(v.name.clone()), span)
+    }
+
+    // bind a declaration to the slot the resolver gave it
+    fn define(&mut self, v: &Variable, value: Value) {
+        if let Some(slot) = v.slot.get() {
+            self.env.borrow_mut().define(slot.index, value);
+        }
+    }
+
+    fn checked(op: &Token, result: Option<i64>, span: Span) -> Result<Value> {
+        result
+            .map(Value::Int)
+            .ok_or_else(|| RuntimeError::new(RuntimeErrorKind::Overflow(op.clone()), span))
+    }
+
+    // a unary operator applied to its evaluated operand
+    fn prefix(op: &Token, right: &Value, span: Span) -> Result<Value> {
+        match (op, right) {
+            (Token::Minus, Value::Int(i)) => Self::checked(op, i.checked_neg(), span),
+            (Token::Minus, Value::Float(fl)) => Ok(Value::Float(-fl)),
+            (Token::Bang, Value::Bool(b)) => Ok(Value::Bool(!b)),
+            _ => Err(Self::invalid(op, &[right], span)),
+        }
+    }
+
+    // a binary operator applied to evaluated operands
+    fn infix(op: &Token, left: &Value, right: &Value, span: Span) -> Result<Value> {
+        match op {
+            Token::Plus => match (left, right) {
+                (Value::Int(l), Value::Int(r)) => Self::checked(op, l.checked_add(*r), span),
+                (Value::Float(l), Value::Float(r)) => Ok(Value::Float(l + r)),
+                (Value::String(l), Value::String(r)) => Ok(Value::String(l.clone() + r)),
+                (Value::Array(l), Value::Array(r)) => {
+                    let elements = l.borrow().iter().chain(r.borrow().iter()).cloned().collect();
+                    Ok(Value::Array(Rc::new(RefCell::new(elements))))
+                }
+                _ => Err(Self::invalid(op, &[left, right], span)),
+            },
+            Token::Minus => match (left, right) {
+                (Value::Int(l), Value::Int(r)) => Self::checked(op, l.checked_sub(*r), span),
+                (Value::Float(l), Value::Float(r)) => Ok(Value::Float(l - r)),
+                _ => Err(Self::invalid(op, &[left, right], span)),
+            },
+            Token::Times => match (left, right) {
+                (Value::Int(l), Value::Int(r)) => Self::checked(op, l.checked_mul(*r), span),
+                (Value::Float(l), Value::Float(r)) => Ok(Value::Float(l * r)),
+                _ => Err(Self::invalid(op, &[left, right], span)),
+            },
+            Token::Divide => match (left, right) {
+                (Value::Int(_), Value::Int(0)) => {
+                    Err(RuntimeError::new(RuntimeErrorKind::DivisionByZero, span))
                 }
+                (Value::Int(l), Value::Int(r)) => Self::checked(op, l.checked_div(*r), span),
+                (Value::Float(l), Value::Float(r)) => Ok(Value::Float(l / r)),
+                _ => Err(Self::invalid(op, &[left, right], span)),
+            },
+            Token::DoubleEqual => Ok(Value::Bool(equal(left, right))),
+            Token::Or => match (left, right) {
+                (Value::Bool(l), Value::Bool(r)) => Ok(Value::Bool(*l || *r)),
+                _ => Err(Self::invalid(op, &[left, right], span)),
+            },
+            Token::And => match (left, right) {
+                (Value::Bool(l), Value::Bool(r)) => Ok(Value::Bool(*l && *r)),
+                _ => Err(Self::invalid(op, &[left, right], span)),
+            },
+            Token::BangEqual => Ok(Value::Bool(!equal(left, right))),
+            Token::Greater => match (left, right) {
+                (Value::Int(l), Value::Int(r)) => Ok(Value::Bool(l > r)),
+                (Value::Float(l), Value::Float(r)) => Ok(Value::Bool(l > r)),
+                _ => Err(Self::invalid(op, &[left, right], span)),
+            },
+            Token::Less => match (left, right) {
+                (Value::Int(l), Value::Int(r)) => Ok(Value::Bool(l < r)),
+                (Value::Float(l), Value::Float(r)) => Ok(Value::Bool(l < r)),
+                _ => Err(Self::invalid(op, &[left, right], span)),
+            },
+            Token::GreaterEqual => match (left, right) {
+                (Value::Int(l), Value::Int(r)) => Ok(Value::Bool(l >= r)),
+                (Value::Float(l), Value::Float(r)) => Ok(Value::Bool(l >= r)),
+                _ => Err(Self::invalid(op, &[left, right], span)),
+            },
+            Token::LessEqual => match (left, right) {
+                (Value::Int(l), Value::Int(r)) => Ok(Value::Bool(l <= r)),
+                (Value::Float(l), Value::Float(r)) => Ok(Value::Bool(l <= r)),
+                _ => Err(Self::invalid(op, &[left, right], span)),
+            },
+            _ => Err(Self::invalid(op, &[left, right], span)),
+        }
+    }
+
+    fn eval_expression(&mut self, expr: &Expression) -> Result<Value> {
+        let span = expr.span;
+        match &expr.kind {
+            ExpressionKind::Integer(i) => Ok(Value::Int(*i)),
+            ExpressionKind::Float(f) => Ok(Value::Float(*f)),
+            ExpressionKind::Boolean(b) => Ok(Value::Bool(*b)),
+            ExpressionKind::String(s) => Ok(Value::String(s.clone())),
+            ExpressionKind::Interpolation(fragments) => self.eval_interpolation(fragments),
+            ExpressionKind::Identifier(v) => {
+                let value = v.slot.get().and_then(|slot| self.env.borrow().get(slot));
+                value.ok_or_else(|| Self::undefined(v, span))
+            }
+            ExpressionKind::Assign(v, expr) => self.eval_assign(v, expr, span),
+            ExpressionKind::Prefix(t, e) => {
+                let right = self.eval_expression(e)?;
+                Self::prefix(t, &right, span)
             }
 
-            Expression::Infix(left, op, right) => {
-                let left = self.eval_expression(left);
-                let right = self.eval_expression(right);
-                match op {
-                    Token::Plus => match (left, right) {
-                        (Value::Int(l), Value::Int(r)) => Value::Int(l + r),
-                        (Value::Float(l), Value::Float(r)) => Value::Float(l + r),
-                        (Value::String(l), Value::String(r)) => Value::String(l + &r),
-                        _ => panic!("Invalid types for +"),
-                    },
-                    Token::Minus => match (left, right) {
-                        (Value::Int(l), Value::Int(r)) => Value::Int(l - r),
-                        (Value::Float(l), Value::Float(r)) => Value::Float(l - r),
-                        _ => panic!("Invalid types for -"),
-                    },
-                    Token::Times => match (left, right) {
-                        (Value::Int(l), Value::Int(r)) => Value::Int(l * r),
-                        (Value::Float(l), Value::Float(r)) => Value::Float(l * r),
-                        _ => panic!("Invalid types for *"),
-                    },
-                    Token::Divide => match (left, right) {
-                        (Value::Int(l), Value::Int(r)) => Value::Int(l / r),
-                        (Value::Float(l), Value::Float(r)) => Value::Float(l / r),
-                        _ => panic!("Invalid types for /"),
-                    },
-                    Token::DoubleEqual => match (left, right) {
-                        (Value::Int(l), Value::Int(r)) => Value::Bool(l == r),
-                        (Value::Float(l), Value::Float(r)) => Value::Bool(l == r),
-                        (Value::Bool(l), Value::Bool(r)) => Value::Bool(l == r),
-                        (Value::String(l), Value::String(r)) => Value::Bool(l == r),
-                        (Value::Null, Value::Null) => Value::Bool(true),
-                        _ => Value::Bool(false),
-                    },
-                    Token::Or => match (left, right) {
-                        (Value::Bool(l), Value::Bool(r)) => Value::Bool(l || r),
-                        _ => panic!("Invalid types for ||"),
-                    },
-                    Token::And => match (left, right) {
-                        (Value::Bool(l), Value::Bool(r)) => Value::Bool(l && r),
-                        _ => panic!("Invalid types for &&"),
-                    },
-                    Token::BangEqual => match (left, right) {
-                        (Value::Int(l), Value::Int(r)) => Value::Bool(l != r),
-                        (Value::Float(l), Value::Float(r)) => Value::Bool(l != r),
-                        (Value::Bool(l), Value::Bool(r)) => Value::Bool(l != r),
-                        (Value::String(l), Value::String(r)) => Value::Bool(l != r),
-                        (Value::Null, Value::Null) => Value::Bool(false),
-                        _ => Value::Bool(true),
-                    },
-                    Token::Greater => match (left, right) {
-                        (Value::Int(l), Value::Int(r)) => Value::Bool(l > r),
-                        (Value::Float(l), Value::Float(r)) => Value::Bool(l > r),
-                        _ => panic!("Invalid types for >"),
-                    },
-                    Token::Less => match (left, right) {
-                        (Value::Int(l), Value::Int(r)) => Value::Bool(l < r),
-                        (Value::Float(l), Value::Float(r)) => Value::Bool(l < r),
-                        _ => panic!("Invalid types for <"),
-                    },
-                    Token::GreaterEqual => match (left, right) {
-                        (Value::Int(l), Value::Int(r)) => Value::Bool(l >= r),
-                        (Value::Float(l), Value::Float(r)) => Value::Bool(l >= r),
-                        _ => panic!("Invalid types for >="),
-                    },
-                    Token::LessEqual => match (left, right) {
-                        (Value::Int(l), Value::Int(r)) => Value::Bool(l <= r),
-                        (Value::Float(l), Value::Float(r)) => Value::Bool(l <= r),
-                        _ => panic!("Invalid types for <="),
-                    },
-                    _ => panic!("Invalid infix operator"),
+            ExpressionKind::Infix(value, Token::DoubleQuestion, default) => self.eval_default(value, default),
+            // the right is only evaluated when the left does not decide it
+            ExpressionKind::Infix(left, op @ (Token::And | Token::Or), right) => {
+                let left = self.eval_expression(left)?;
+                match (op, &left) {
+                    (Token::And, Value::Bool(false)) | (Token::Or, Value::Bool(true)) => Ok(left),
+                    _ => {
+                        let right = self.eval_expression(right)?;
+                        Self::infix(op, &left, &right, span)
+                    }
                 }
             }
+            ExpressionKind::Infix(left, op, right) => {
+                let left = self.eval_expression(left)?;
+                let right = self.eval_expression(right)?;
+                Self::infix(op, &left, &right, span)
+            }
 
-            _ => Value::Null,
+            ExpressionKind::Call(callee, args) => self.eval_call(callee, args, span),
+            ExpressionKind::Method(receiver, name, args) => self.eval_method(receiver, name, args, false, span),
+            ExpressionKind::SafeMethod(receiver, name, args) => self.eval_method(receiver, name, args, true, span),
+            ExpressionKind::Array(elements) => {
+                let values = self.eval_all(elements)?;
+                Ok(Value::Array(Rc::new(RefCell::new(values))))
+            }
+            ExpressionKind::Map(entries) => self.eval_map(entries, span),
+            ExpressionKind::Index(target, i) => self.eval_index(target, i, span),
+            ExpressionKind::Slice(target, start, end) => self.eval_slice(target, start, end, span),
+            ExpressionKind::SetIndex(target, i, value) => self.eval_set_index(target, i, value, span),
+            ExpressionKind::Instance(name, fields) => self.eval_instance(name, fields, span),
+            ExpressionKind::Field(target, name) => {
+                let target = self.eval_expression(target)?;
+                field(&target, name).map_err(|kind| RuntimeError::new(kind, span))
+            }
+            ExpressionKind::SafeField(target, name) => match self.eval_expression(target)? {
+                Value::Null => Ok(Value::Null),
+                target => field(&target, name).map_err(|kind| RuntimeError::new(kind, span)),
+            },
+            ExpressionKind::SetField(target, name, value) => self.eval_set_field(target, name, value, span),
+            ExpressionKind::Variant(name, v, args) => Ok(variant(name, v, self.eval_all(args)?)),
+            ExpressionKind::Match(subject, arms) => self.eval_match(subject, arms, span),
+            ExpressionKind::Lambda(f) => Ok(self.closure(f)),
+            ExpressionKind::Null => Ok(Value::Null),
         }
     }
 
-    fn eval_statement(&mut self, x: &Statement) -> Value {
-        match x {
-            Statement::Expression(e) => {
-                return self.eval_expression(&e);
-            },
-            Statement::Print(expr) => {
-                let value = self.eval_expression(&expr);
+    // the arms below are kept out of `eval_expression` so that its stack
+    // frame, taken by every nested expression, stays small
+
+    fn eval_all(&mut self, exprs: &[Expression]) -> Result<Vec<Value>> {
+        let mut values = Vec::new();
+        for e in exprs {
+            values.push(self.eval_expression(e)?);
+        }
+        Ok(values)
+    }
+
+    fn eval_assign(&mut self, v: &Variable, expr: &Expression, span: Span) -> Result<Value> {
+        let e = self.eval_expression(expr)?;
+        let slot = v.slot.get().ok_or_else(|| Self::undefined(v, span))?;
+        self.env.borrow_mut().assign(slot, e.clone()).map_err(|_| Self::undefined(v, span))?;
+        Ok(e)
+    }
+
+    fn eval_index(&mut self, target: &Expression, i: &Expression, span: Span) -> Result<Value> {
+        let target = self.eval_expression(target)?;
+        let i = self.eval_expression(i)?;
+        index(&target, &i).map_err(|kind| RuntimeError::new(kind, span))
+    }
+
+    fn eval_interpolation(&mut self, fragments: &[Fragment]) -> Result<Value> {
+        let mut s = String::new();
+        for fragment in fragments {
+            match fragment {
+                Fragment::Text(text) => s.push_str(text),
+                Fragment::Expression(e) => s.push_str(&self.eval_expression(e)?.to_string()),
+            }
+        }
+        Ok(Value::String(s))
+    }
+
+    fn eval_call(&mut self, callee: &Expression, args: &[Expression], span: Span) -> Result<Value> {
+        let callee = self.eval_expression(callee)?;
+        let values = self.eval_all(args)?;
+        match callee {
+            Value::Function(closure) => self.call(&closure, values, span),
+            other => Err(RuntimeError::new(RuntimeErrorKind::NotCallable(other.type_name()), span)),
+        }
+    }
+
+    // with `safe` set a null receiver gives null, without evaluating the
+    // arguments
+    fn eval_method(
+        &mut self,
+        receiver: &Expression,
+        name: &str,
+        args: &[Expression],
+        safe: bool,
+        span: Span,
+    ) -> Result<Value> {
+        let receiver = self.eval_expression(receiver)?;
+        if safe && receiver == Value::Null {
+            return Ok(Value::Null);
+        }
+        let mut values = self.eval_all(args)?;
+        // a struct method is called with the receiver as `self`
+        if let Some(closure) = struct_method(&self.structs, &receiver, name) {
+            values.insert(0, receiver);
+            return self.call(&closure, values, span);
+        }
+        call_method(&receiver, name, values).map_err(|kind| RuntimeError::new(kind, span))
+    }
+
+    // the default is only evaluated when the value is null
+    fn eval_default(&mut self, value: &Expression, default: &Expression) -> Result<Value> {
+        match self.eval_expression(value)? {
+            Value::Null => self.eval_expression(default),
+            value => Ok(value),
+        }
+    }
+
+    fn eval_map(&mut self, entries: &[(Expression, Expression)], span: Span) -> Result<Value> {
+        let map = Value::Map(Rc::new(RefCell::new(IndexMap::new())));
+        for (key, value) in entries {
+            let key = self.eval_expression(key)?;
+            let value = self.eval_expression(value)?;
+            set_index(&map, &key, value).map_err(|kind| RuntimeError::new(kind, span))?;
+        }
+        Ok(map)
+    }
+
+    fn eval_slice(
+        &mut self,
+        target: &Expression,
+        start: &Option<Box<Expression>>,
+        end: &Option<Box<Expression>>,
+        span: Span,
+    ) -> Result<Value> {
+        let target = self.eval_expression(target)?;
+        let mut bound = |e: &Option<Box<Expression>>| match e {
+            Some(e) => self.eval_expression(e),
+            None => Ok(Value::Null),
+        };
+        let start = bound(start)?;
+        let end = bound(end)?;
+        slice(&target, &start, &end).map_err(|kind| RuntimeError::new(kind, span))
+    }
+
+    fn eval_set_index(&mut self, target: &Expression, i: &Expression, value: &Expression, span: Span) -> Result<Value> {
+        let target = self.eval_expression(target)?;
+        let i = self.eval_expression(i)?;
+        let value = self.eval_expression(value)?;
+        set_index(&target, &i, value.clone()).map_err(|kind| RuntimeError::new(kind, span))?;
+        Ok(value)
+    }
+
+    fn eval_instance(&mut self, name: &str, fields: &[(String, Expression)], span: Span) -> Result<Value> {
+        let mut values = Vec::new();
+        for (field, value) in fields {
+            values.push((field.clone(), self.eval_expression(value)?));
+        }
+        instance(&self.structs, name, values).map_err(|kind| RuntimeError::new(kind, span))
+    }
+
+    fn eval_set_field(&mut self, target: &Expression, name: &str, value: &Expression, span: Span) -> Result<Value> {
+        let target = self.eval_expression(target)?;
+        let value = self.eval_expression(value)?;
+        set_field(&target, name, value.clone()).map_err(|kind| RuntimeError::new(kind, span))?;
+        Ok(value)
+    }
+
+    // the first arm whose pattern matches and whose guard holds gives the
+    // value. Each arm that matches gets a scope for the names it binds
+    fn eval_match(&mut self, subject: &Expression, arms: &[Arm], span: Span) -> Result<Value> {
+        let value = self.eval_expression(subject)?;
+        for arm in arms {
+            let mut bindings = Vec::new();
+            if !matches(&arm.pattern, &value, &mut bindings) {
+                continue;
+            }
+            let mut scope = Environment::new_from(self.env.clone());
+            for (index, value) in bindings {
+                scope.define(index, value);
+            }
+            let outer = std::mem::replace(&mut self.env, Rc::new(RefCell::new(scope)));
+            let result = match &arm.guard {
+                Some(guard) => self.eval_expression(guard).map(|g| g.is_truthy()),
+                None => Ok(true),
+            };
+            let result = match result {
+                Ok(true) => self.eval_expression(&arm.body).map(Some),
+                other => other.map(|_| None),
+            };
+            self.env = outer;
+            if let Some(value) = result? {
+                return Ok(value);
+            }
+        }
+        Err(RuntimeError::new(no_match(&value), span))
+    }
+
+    // run a closure in a fresh frame hanging off the scope it captured
+    fn call(&mut self, closure: &Closure, args: Vec<Value>, span: Span) -> Result<Value> {
+        let f = &closure.function;
+        if f.params.len() != args.len() {
+            let kind = RuntimeErrorKind::WrongArgumentCount(f.params.len(), args.len());
+            return Err(RuntimeError::new(kind, span));
+        }
+        if self.depth >= MAX_CALL_DEPTH {
+            return Err(RuntimeError::new(RuntimeErrorKind::StackOverflow(MAX_CALL_DEPTH), span));
+        }
+
+        let mut frame = Environment::new_from(closure.env.clone());
+        for (index, arg) in args.into_iter().enumerate() {
+            frame.define(index, arg);
+        }
+
+        let caller = std::mem::replace(&mut self.env, Rc::new(RefCell::new(frame)));
+        self.depth += 1;
+        let result = self.eval_block(&f.body);
+        self.depth -= 1;
+        self.env = caller;
+
+        match result {
+            Ok(Flow::Return(value)) => Ok(value),
+            Ok(Flow::Normal(_)) => Ok(Value::Null),
+            Err(e) => Err(e.within(&format!("function '{}'", f.name), span)),
+        }
+    }
+
+    // run `statements` in a new scope nested in the current one
+    fn eval_scoped(&mut self, statements: &[Statement]) -> Result<Flow> {
+        let scope = Environment::new_from(self.env.clone());
+        let outer = std::mem::replace(&mut self.env, Rc::new(RefCell::new(scope)));
+        let result = self.eval_block(statements);
+        self.env = outer;
+        result
+    }
+
+    fn eval_block(&mut self, statements: &[Statement]) -> Result<Flow> {
+        let mut result = Value::Null;
+        for statement in statements {
+            match self.eval_statement(statement)? {
+                Flow::Normal(value) => result = value,
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Normal(result))
+    }
+
+    fn eval_statement(&mut self, x: &Statement) -> Result<Flow> {
+        match &x.kind {
+            StatementKind::Expression(e) => Ok(Flow::Normal(self.eval_expression(e)?)),
+            StatementKind::Print(expr) => {
+                let value = self.eval_expression(expr)?;
                 println!("{}", value);
-                return value;
+                Ok(Flow::Normal(value))
             }
-            Statement::If(condition, consequence) => {
-                let condition = self.eval_expression(&condition);
-                if let Value::Bool(b) = condition {
-                    if b {
-                        self.eval_statement(&consequence);
+            StatementKind::If(condition, consequence) => {
+                let condition = self.eval_expression(condition)?;
+                if let Value::Bool(true) = condition {
+                    if let Flow::Return(value) = self.eval_statement(consequence)
+                        .map_err(|e| e.within("if statement", x.span))? {
+                        return Ok(Flow::Return(value));
                     }
                 }
-                return Value::Null;
-            }
-            Statement::IfElse(condition, consequence, alternative) => {
-                let condition = self.eval_expression(&condition);
-                if let Value::Bool(b) = condition {
-                    if b {
-                        self.eval_statement(&consequence);
-                    } else {
-                        self.eval_statement(&alternative);
+                Ok(Flow::Normal(Value::Null))
+            }
+            StatementKind::IfElse(condition, consequence, alternative) => {
+                let condition = self.eval_expression(condition)?;
+                let flow = match condition {
+                    Value::Bool(true) => self.eval_statement(consequence)
+                        .map_err(|e| e.within("if statement", x.span))?,
+                    Value::Bool(false) => self.eval_statement(alternative)
+                        .map_err(|e| e.within("else branch", alternative.span))?,
+                    _ => Flow::Normal(Value::Null),
+                };
+                match flow {
+                    Flow::Return(value) => Ok(Flow::Return(value)),
+                    Flow::Normal(_) => Ok(Flow::Normal(Value::Null)),
+                }
+            }
+            StatementKind::Let(v, _ty, expr) => {
+                let e = self.eval_expression(expr)?;
+                self.define(v, e);
+                Ok(Flow::Normal(Value::Null))
+            }
+            StatementKind::While(expr, s) => {
+                while self.eval_expression(expr)?.is_truthy() {
+                    if let Flow::Return(value) = self.eval_statement(s)
+                        .map_err(|e| e.within("while loop", x.span))? {
+                        return Ok(Flow::Return(value));
                     }
                 }
-                return Value::Null;
-            }
-            Statement::Let(name, _ty, expr) => {
-                let e = self.eval_expression(&expr);
-                self.env.define(name.to_string(), e);
-                return Value::Null;
-            }
-            Statement::While(expr, s) => {
-               while self.eval_expression(&expr).is_truthy() {
-                   self.eval_statement(&s);
-               }
-                return Value::Null;
-            }
-            Statement::Block(statements) => {
-                let mut result = Value::Null;
-                for statement in statements {
-                    result = self.eval_statement(&statement);
+                Ok(Flow::Normal(Value::Null))
+            }
+            StatementKind::Block(statements) => self.eval_scoped(statements),
+            StatementKind::Function(v, f) => {
+                let closure = self.closure(f);
+                self.define(v, closure);
+                Ok(Flow::Normal(Value::Null))
+            }
+            StatementKind::Return(value) => {
+                let value = match value {
+                    Some(e) => self.eval_expression(e)?,
+                    None => Value::Null,
+                };
+                Ok(Flow::Return(value))
+            }
+            StatementKind::Struct(s) => {
+                self.structs.insert(s.name.clone(), StructType::new(s.clone()));
+                Ok(Flow::Normal(Value::Null))
+            }
+            // variants carry their enum's name, so there is nothing to register
+            StatementKind::Enum(_) => Ok(Flow::Normal(Value::Null)),
+            StatementKind::Impl(name, methods) => {
+                for method in methods {
+                    if let Value::Function(closure) = self.closure(method) {
+                        if let Some(ty) = self.structs.get_mut(name) {
+                            ty.methods.insert(method.name.clone(), closure);
+                        }
+                    }
                 }
-                return result;
+                Ok(Flow::Normal(Value::Null))
             }
         }
     }
 
-    pub fn eval(&mut self, ast: Vec<Statement>) -> Vec<Value> {
+    pub fn eval(&mut self, ast: Vec<Statement>) -> Result<Vec<Value>> {
+        // top level functions are defined before anything runs, so they can
+        // call each other whatever their order
+        for x in &ast {
+            if let StatementKind::Function(v, f) = &x.kind {
+                let closure = self.closure(f);
+                self.define(v, closure);
+            }
+        }
         let mut values = Vec::new();
         for x in ast {
-            values.push(self.eval_statement(&x));
+            if let StatementKind::Function(..) = x.kind {
+                values.push(Value::Null);
+                continue;
+            }
+            match self.eval_statement(&x)? {
+                Flow::Normal(value) | Flow::Return(value) => values.push(value),
+            }
         }
-        values
+        Ok(values)
     }
 }
@@ -0,0 +1,486 @@
+use crate::ast::*;
+use crate::tokens::{Lexeme, Span, Token};
+
+const INDENT: &str = "  ";
+
+// how tightly an expression binds, an operand that binds more loosely than
+// its operator needs parentheses
+fn precedence(expr: &Expression) -> u8 {
+    match &expr.kind {
+        ExpressionKind::Assign(..) | ExpressionKind::SetIndex(..) | ExpressionKind::SetField(..) => 1,
+        ExpressionKind::Infix(_, op, _) => match op {
+            Token::Or => 2,
+            Token::And => 3,
+            Token::DoubleEqual | Token::BangEqual => 4,
+            Token::Greater | Token::GreaterEqual | Token::Less | Token::LessEqual => 5,
+            Token::DoubleQuestion => 6,
+            Token::Plus | Token::Minus => 7,
+            _ => 8,
+        },
+        ExpressionKind::Prefix(..) => 9,
+        ExpressionKind::Call(..) | ExpressionKind::Method(..) | ExpressionKind::SafeMethod(..) |
+            ExpressionKind::Index(..) | ExpressionKind::Slice(..) | ExpressionKind::Field(..) |
+            ExpressionKind::SafeField(..) => 10,
+        _ => 11,
+    }
+}
+
+// a function body of just a tail expression, which lambdas keep on one line
+fn tail_only(f: &Function) -> Option<&Expression> {
+    match f.body.as_slice() {
+        [stmt] => match &stmt.kind {
+            StatementKind::Return(Some(e)) if e.span == stmt.span => Some(e),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// lays a parsed program out the one way `mt fmt` accepts: two space indents,
+// opening braces on the line that opens them, spaces around binary operators
+// and only the parentheses precedence needs. One blank line between
+// statements is kept, longer runs are collapsed. Comments go on their own line
+// before the statement that follows them, or stay at the end of the line of
+// the statement they follow. A list with comments in it keeps one item per
+// line so they stay next to their items, and comments anywhere else in an
+// expression go before its statement
+pub struct Formatter<'a> {
+    source: &'a str,
+    // byte offset of the start of every line, to find blank lines
+    lines: Vec<usize>,
+    // every comment in the source, in order
+    comments: &'a [Lexeme],
+    // the first comment not written yet
+    next: usize,
+    indent: usize,
+    out: String,
+}
+
+impl<'a> Formatter<'a> {
+    pub fn new(source: &'a str, comments: &'a [Lexeme]) -> Formatter<'a> {
+        let mut lines = vec![0];
+        lines.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+        Formatter {
+            source,
+            lines,
+            comments,
+            next: 0,
+            indent: 0,
+            out: String::new(),
+        }
+    }
+
+    // a formatter writing at the same indentation, for expressions that
+    // contain statements
+    fn nested(&self) -> Formatter<'a> {
+        Formatter {
+            source: self.source,
+            lines: self.lines.clone(),
+            comments: self.comments,
+            next: self.next,
+            indent: self.indent,
+            out: String::new(),
+        }
+    }
+
+    // 1-based line of a byte offset
+    fn line(&self, offset: usize) -> usize {
+        self.lines.partition_point(|&start| start <= offset)
+    }
+
+    fn newline(&mut self) {
+        self.out.push('\n');
+        self.out.push_str(&INDENT.repeat(self.indent));
+    }
+
+    fn last_line(&self, span: Span) -> usize {
+        self.line(span.end.saturating_sub(1))
+    }
+
+    // the next unwritten comment if it starts before `offset`
+    fn comment_before(&self, offset: usize) -> Option<&'a Lexeme> {
+        self.comments.get(self.next).filter(|c| c.span.start < offset)
+    }
+
+    fn comment(&mut self, comment: &Lexeme) {
+        self.out.push_str(&comment.token.to_string());
+        self.next += 1;
+    }
+
+    // start a new line for something at `span`, with a blank line before it
+    // if the source had one. `last` is the line the previous thing ended on,
+    // None at the start of a block
+    fn separate(&mut self, last: Option<usize>, span: Span) {
+        if let Some(last) = last {
+            if self.line(span.start) > last + 1 {
+                self.out.push('\n');
+            }
+            self.newline();
+        }
+    }
+
+    // statements and the comments among them, one per line at the current
+    // indentation, then the comments left before `end`. The first starts
+    // where the output is now
+    fn statements(&mut self, stmts: &[Statement], end: usize) {
+        self.lines(stmts, end, |s| s.span, Self::statement);
+    }
+
+    // the same for any items, such as struct fields, written by `write`
+    fn lines<T>(&mut self, items: &[T], end: usize, span: fn(&T) -> Span, write: fn(&mut Self, &T)) {
+        let mut last = None;
+        for (i, item) in items.iter().enumerate() {
+            // a comment at the end of a line goes after the last item on it
+            let following = items.get(i + 1).map_or(end, |next| span(next).start);
+            let span = span(item);
+            while let Some(c) = self.comment_before(span.start) {
+                self.separate(last, c.span);
+                self.comment(c);
+                last = Some(self.last_line(c.span));
+            }
+            self.separate(last, span);
+            if self.comment_before(span.end).is_some() {
+                let mut nested = self.nested();
+                write(&mut nested, item);
+                self.next = nested.next;
+                while let Some(c) = self.comment_before(span.end) {
+                    self.comment(c);
+                    self.newline();
+                }
+                self.out.push_str(&nested.out);
+            } else {
+                write(self, item);
+            }
+            last = Some(self.last_line(span));
+
+            if let Some(c) = self.comment_before(following) {
+                if c.span.start >= span.end && Some(c.span.line) == last {
+                    self.out.push(' ');
+                    self.comment(c);
+                    last = Some(self.last_line(c.span));
+                }
+            }
+        }
+        while let Some(c) = self.comment_before(end) {
+            self.separate(last, c.span);
+            self.comment(c);
+            last = Some(self.last_line(c.span));
+        }
+    }
+
+    // a block ending at `end`, the offset just past its '}'
+    fn block(&mut self, stmts: &[Statement], end: usize) {
+        self.braced(stmts, end, |s| s.span, Self::statement);
+    }
+
+    // items in braces, one per line, like a block
+    fn braced<T>(&mut self, items: &[T], end: usize, span: fn(&T) -> Span, write: fn(&mut Self, &T)) {
+        if items.is_empty() && self.comment_before(end).is_none() {
+            self.out.push_str("{}");
+            return;
+        }
+        self.out.push('{');
+        self.indent += 1;
+        self.newline();
+        self.lines(items, end, span, write);
+        self.indent -= 1;
+        self.newline();
+        self.out.push('}');
+    }
+
+    // the items of a list written by `write`, each followed by a comma, one
+    // per line between `open` and `close`. For lists with comments in them
+    fn spread<T>(&mut self, open: &str, items: &[T], close: &str, end: usize, span: fn(&T) -> Span,
+                 write: fn(&mut Self, &T)) -> String {
+        let mut nested = self.nested();
+        nested.out.push_str(open);
+        nested.indent += 1;
+        nested.newline();
+        nested.lines(items, end, span, write);
+        nested.indent -= 1;
+        nested.newline();
+        nested.out.push_str(close);
+        self.next = nested.next;
+        nested.out
+    }
+
+    // expressions separated by commas, on one line unless there are comments
+    // among them before `end`
+    fn list(&mut self, open: &str, items: &[Expression], close: &str, end: usize) -> String {
+        if self.comment_before(end).is_some() {
+            return self.spread(open, items, close, end, |e| e.span, |formatter, e| {
+                let e = formatter.expression(e);
+                formatter.out.push_str(&format!("{},", e));
+            });
+        }
+        let items: Vec<String> = items.iter().map(|e| self.expression(e)).collect();
+        format!("{}{}{}", open, items.join(", "), close)
+    }
+
+    // the body of an `if`, `else` or `while`, kept on the same line
+    fn body(&mut self, stmt: &Statement) {
+        match &stmt.kind {
+            StatementKind::Block(stmts) => self.block(stmts, stmt.span.end),
+            _ => self.statement(stmt),
+        }
+    }
+
+    fn function(&mut self, f: &Function, name: Option<&str>) {
+        self.out.push_str("fn");
+        if let Some(name) = name {
+            self.out.push(' ');
+            self.out.push_str(name);
+        }
+        // a method's `self` has no written type
+        let params: Vec<String> = f
+            .params
+            .iter()
+            .map(|p| if p.name == "self" { p.name.clone() } else { format!("{}: {}", p.name, p.ty) })
+            .collect();
+        self.out.push_str(&format!("({})", params.join(", ")));
+        if f.return_type != TypeAnnotation::Named("null".to_string()) {
+            self.out.push_str(&format!(" -> {}", f.return_type));
+        }
+        self.out.push(' ');
+        match tail_only(f) {
+            Some(e) if name.is_none() && self.comment_before(f.span.end).is_none() => {
+                let e = self.expression(e);
+                self.out.push_str(&format!("{{ {} }}", e));
+            }
+            _ => self.block(&f.body, f.span.end),
+        }
+    }
+
+    fn statement(&mut self, stmt: &Statement) {
+        match &stmt.kind {
+            StatementKind::Expression(e) => {
+                let e = self.expression(e);
+                self.out.push_str(&format!("{};", e));
+            }
+            StatementKind::Print(e) => {
+                let e = self.expression(e);
+                self.out.push_str(&format!("print {};", e));
+            }
+            StatementKind::Let(v, ty, e) => {
+                let e = self.expression(e);
+                self.out.push_str(&format!("let {}: {} = {};", v.name, ty, e));
+            }
+            StatementKind::Return(None) => self.out.push_str("return;"),
+            // a tail expression has the span of the expression itself
+            StatementKind::Return(Some(e)) if e.span == stmt.span => {
+                let e = self.expression(e);
+                self.out.push_str(&e);
+            }
+            StatementKind::Return(Some(e)) => {
+                let e = self.expression(e);
+                self.out.push_str(&format!("return {};", e));
+            }
+            StatementKind::Block(stmts) => self.block(stmts, stmt.span.end),
+            StatementKind::If(condition, consequence) => {
+                let condition = self.expression(condition);
+                self.out.push_str(&format!("if ({}) ", condition));
+                self.body(consequence);
+            }
+            StatementKind::IfElse(condition, consequence, alternative) => {
+                let condition = self.expression(condition);
+                self.out.push_str(&format!("if ({}) ", condition));
+                self.body(consequence);
+                if matches!(consequence.kind, StatementKind::Block(_)) {
+                    self.out.push(' ');
+                } else {
+                    self.newline();
+                }
+                self.out.push_str("else ");
+                self.body(alternative);
+            }
+            StatementKind::While(condition, body) => {
+                let condition = self.expression(condition);
+                self.out.push_str(&format!("while ({}) ", condition));
+                self.body(body);
+            }
+            StatementKind::Function(v, f) => self.function(f, Some(&v.name)),
+            StatementKind::Struct(s) => {
+                self.out.push_str(&format!("struct {} ", s.name));
+                self.braced(&s.fields, s.span.end, |f| f.span, |formatter, f| {
+                    formatter.out.push_str(&format!("{}: {},", f.name, f.ty));
+                });
+            }
+            StatementKind::Enum(e) => {
+                self.out.push_str(&format!("enum {} ", e.name));
+                self.braced(&e.variants, e.span.end, |v| v.span, |formatter, v| {
+                    formatter.out.push_str(&v.name);
+                    if !v.fields.is_empty() {
+                        let fields: Vec<String> = v.fields.iter().map(|f| f.to_string()).collect();
+                        formatter.out.push_str(&format!("({})", fields.join(", ")));
+                    }
+                    formatter.out.push(',');
+                });
+            }
+            StatementKind::Impl(name, methods) => {
+                self.out.push_str(&format!("impl {} ", name));
+                self.braced(methods, stmt.span.end, |m| m.span, |formatter, m| {
+                    formatter.function(m, Some(&m.name));
+                });
+            }
+        }
+    }
+
+    // one arm of a match, on its own line
+    fn arm(&mut self, arm: &Arm) {
+        let mut s = self.pattern(&arm.pattern);
+        if let Some(guard) = &arm.guard {
+            s.push_str(&format!(" if {}", self.expression(guard)));
+        }
+        let body = self.expression(&arm.body);
+        self.out.push_str(&format!("{} => {},", s, body));
+    }
+
+    // numbers and strings as written, like in expressions
+    fn pattern(&self, pattern: &Pattern) -> String {
+        match &pattern.kind {
+            PatternKind::Integer(_) | PatternKind::Float(_) | PatternKind::String(_) => {
+                let text = &self.source[pattern.span.start..pattern.span.end];
+                match text.strip_prefix('-') {
+                    Some(number) => format!("-{}", number.trim_start()),
+                    None => text.to_string(),
+                }
+            }
+            PatternKind::Variant(name, variant, patterns) if !patterns.is_empty() => {
+                let patterns: Vec<String> = patterns.iter().map(|p| self.pattern(p)).collect();
+                format!("{}::{}({})", name, variant, patterns.join(", "))
+            }
+            _ => pattern.to_string(),
+        }
+    }
+
+    // `expr`, in parentheses if it binds more loosely than `min`
+    fn operand(&mut self, expr: &Expression, min: u8) -> String {
+        let s = self.expression(expr);
+        if precedence(expr) < min {
+            format!("({})", s)
+        } else {
+            s
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression) -> String {
+        let p = precedence(expr);
+        match &expr.kind {
+            // numbers and strings as written, in any base or with escapes.
+            // The span of a parenthesized expression takes in the parentheses
+            ExpressionKind::Integer(_) | ExpressionKind::Float(_) | ExpressionKind::String(_) |
+                ExpressionKind::Interpolation(_) => self.source[expr.span.start..expr.span.end]
+                .trim_start_matches(|c: char| c == '(' || c.is_whitespace())
+                .trim_end_matches(|c: char| c == ')' || c.is_whitespace())
+                .to_string(),
+            ExpressionKind::Boolean(b) => b.to_string(),
+            ExpressionKind::Null => "null".to_string(),
+            ExpressionKind::Identifier(v) => v.name.clone(),
+            ExpressionKind::Assign(v, e) => format!("{} = {}", v.name, self.expression(e)),
+            // `--1` does not parse, so a nested prefix keeps its parentheses
+            ExpressionKind::Prefix(op, e) => format!("{}{}", op, self.operand(e, p + 1)),
+            // `??` associates to the right, so an equal left operand keeps
+            // its parentheses
+            ExpressionKind::Infix(left, Token::DoubleQuestion, right) => {
+                format!("{} ?? {}", self.operand(left, p + 1), self.operand(right, p))
+            }
+            // the others associate to the left, so an equal right operand
+            // keeps its parentheses
+            ExpressionKind::Infix(left, op, right) => {
+                format!("{} {} {}", self.operand(left, p), op, self.operand(right, p + 1))
+            }
+            ExpressionKind::Call(callee, args) => {
+                let callee = self.operand(callee, p);
+                let args = self.list("(", args, ")", expr.span.end);
+                format!("{}{}", callee, args)
+            }
+            ExpressionKind::Method(receiver, name, args) => {
+                let receiver = self.operand(receiver, p);
+                let args = self.list("(", args, ")", expr.span.end);
+                format!("{}.{}{}", receiver, name, args)
+            }
+            ExpressionKind::SafeMethod(receiver, name, args) => {
+                let receiver = self.operand(receiver, p);
+                let args = self.list("(", args, ")", expr.span.end);
+                format!("{}?.{}{}", receiver, name, args)
+            }
+            ExpressionKind::Array(elements) => self.list("[", elements, "]", expr.span.end),
+            ExpressionKind::Map(entries) if self.comment_before(expr.span.end).is_some() => {
+                self.spread("{", entries, "}", expr.span.end, |(k, v)| k.span.to(v.span), |formatter, (k, v)| {
+                    let entry = format!("{}: {},", formatter.expression(k), formatter.expression(v));
+                    formatter.out.push_str(&entry);
+                })
+            }
+            ExpressionKind::Map(entries) => {
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", self.expression(k), self.expression(v)))
+                    .collect();
+                format!("{{{}}}", entries.join(", "))
+            }
+            ExpressionKind::Index(target, index) => {
+                format!("{}[{}]", self.operand(target, p), self.expression(index))
+            }
+            // an open bound is left out, `a[:]` copies the whole array
+            ExpressionKind::Slice(target, start, end) => {
+                let target = self.operand(target, p);
+                let start = start.as_ref().map(|e| self.expression(e)).unwrap_or_default();
+                let end = end.as_ref().map(|e| self.expression(e)).unwrap_or_default();
+                format!("{}[{}:{}]", target, start, end)
+            }
+            ExpressionKind::SetIndex(target, index, value) => {
+                let target = self.operand(target, 10);
+                format!("{}[{}] = {}", target, self.expression(index), self.expression(value))
+            }
+            ExpressionKind::Instance(name, fields) if self.comment_before(expr.span.end).is_some() => {
+                let fields = self.spread("{", fields, "}", expr.span.end, |(_, value)| value.span, |formatter, field| {
+                    let field = format!("{}: {},", field.0, formatter.expression(&field.1));
+                    formatter.out.push_str(&field);
+                });
+                format!("{} {}", name, fields)
+            }
+            ExpressionKind::Instance(name, fields) if fields.is_empty() => format!("{} {{}}", name),
+            ExpressionKind::Instance(name, fields) => {
+                let fields: Vec<String> =
+                    fields.iter().map(|(f, value)| format!("{}: {}", f, self.expression(value))).collect();
+                format!("{} {{ {} }}", name, fields.join(", "))
+            }
+            ExpressionKind::Field(target, field) => format!("{}.{}", self.operand(target, p), field),
+            ExpressionKind::SafeField(target, field) => format!("{}?.{}", self.operand(target, p), field),
+            ExpressionKind::SetField(target, field, value) => {
+                let target = self.operand(target, 10);
+                format!("{}.{} = {}", target, field, self.expression(value))
+            }
+            ExpressionKind::Variant(name, variant, args) if args.is_empty() => format!("{}::{}", name, variant),
+            ExpressionKind::Variant(name, variant, args) => {
+                let args = self.list("(", args, ")", expr.span.end);
+                format!("{}::{}{}", name, variant, args)
+            }
+            ExpressionKind::Match(subject, arms) => {
+                let subject = self.expression(subject);
+                let mut nested = self.nested();
+                nested.out.push_str(&format!("match ({}) ", subject));
+                nested.braced(arms, expr.span.end, |a| a.pattern.span.to(a.body.span), Self::arm);
+                self.next = nested.next;
+                nested.out
+            }
+            ExpressionKind::Lambda(f) => {
+                let mut nested = self.nested();
+                nested.function(f, None);
+                self.next = nested.next;
+                nested.out
+            }
+        }
+    }
+}
+
+// the canonical layout of a program, parsed from `source`. `comments` are the
+// trivia of its tokens
+pub fn format(source: &str, ast: &[Statement], comments: &[Lexeme]) -> String {
+    let mut formatter = Formatter::new(source, comments);
+    formatter.statements(ast, source.len());
+    if !formatter.out.is_empty() {
+        formatter.out.push('\n');
+    }
+    formatter.out
+}
@@ -1,96 +1,589 @@
+use unicode_ident::{is_xid_continue, is_xid_start};
+
 use crate::tokens;
+use crate::diagnostics::Diagnostic;
+
+// every keyword and the token it lexes to
+pub const KEYWORDS: &[(&str, tokens::Token)] = &[
+    ("print", tokens::Token::Print),
+    ("true", tokens::Token::Bool(true)),
+    ("false", tokens::Token::Bool(false)),
+    ("let", tokens::Token::Let),
+    ("if", tokens::Token::If),
+    ("else", tokens::Token::Else),
+    ("while", tokens::Token::While),
+    ("null", tokens::Token::Null),
+    ("fn", tokens::Token::Fn),
+    ("return", tokens::Token::Return),
+    ("struct", tokens::Token::Struct),
+    ("impl", tokens::Token::Impl),
+    ("self", tokens::Token::SelfValue),
+    ("enum", tokens::Token::Enum),
+    ("match", tokens::Token::Match),
+];
+
+// a piece of a string literal being read: a range of its source text, or the
+// tokens of an expression in braces
+enum Piece {
+    Text(usize, usize),
+    Code(Vec<tokens::Lexeme>),
+}
 
 pub struct Lexer {
     input: String,
-    position: usize,
-    line: usize,
-    read_position: usize,
     ch: char,
+    // byte offsets of `ch` and of the character after it
+    offset: usize,
+    read_offset: usize,
+    // 1-based line and column, in characters, of `ch`
+    line: usize,
+    column: usize,
+    errors: Vec<Diagnostic>,
 }
 
 impl Lexer {
     // constructor
     pub fn new(input: String) -> Lexer {
         let mut l = Lexer {
-            input: input,
-            position: 0,
-            line: 1,
-            read_position: 0,
+            input,
             ch: '\0',
+            offset: 0,
+            read_offset: 0,
+            line: 1,
+            column: 1,
+            errors: Vec::new(),
         };
         
         l.read_char();
         l
     }
 
-    // read a character from the input string and advance the position
+    // move on to the next character of the input, '\0' at the end
     fn read_char(&mut self) {
-        if self.read_position >= self.input.len() {
-            self.ch = '\0';
-        } else {
-            self.ch = self.input.chars().nth(self.read_position).unwrap();
+        if self.read_offset > self.offset {
+            if self.ch == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+        self.offset = self.read_offset;
+        self.ch = self.input[self.offset..].chars().next().unwrap_or('\0');
+        if self.offset < self.input.len() {
+            self.read_offset += self.ch.len_utf8();
         }
-        self.position = self.read_position;
-        self.read_position += 1;
     }
 
+    // make the next `read_char` read the current character again
     fn back_char(&mut self) {
-        self.read_position -= 1;
-        self.position = self.read_position;
-        self.ch = self.input.chars().nth(self.position).unwrap();
+        self.read_offset = self.offset;
+    }
+
+    // the character after the current one
+    fn peek_char(&self) -> char {
+        self.input[self.read_offset..].chars().next().unwrap_or('\0')
+    }
+
+    fn invalid_number(&mut self, span: tokens::Span, label: String) {
+        let d = Diagnostic::error("E0007", "Invalid number literal".to_string(), span).with_label(label);
+        self.errors.push(d);
+    }
+
+    fn out_of_range(&mut self, span: tokens::Span, ty: &str) {
+        let d = Diagnostic::error("E0006", "Number literal out of range".to_string(), span)
+            .with_label(format!("does not fit in {}", ty));
+        let d = match ty {
+            "int64" => d.with_note(format!("int64 values go from {} to {}", i64::MIN, i64::MAX)),
+            _ => d,
+        };
+        self.errors.push(d);
+    }
+
+    // read a `0x`, `0b` or `0o` integer, the '0' is the current character
+    fn read_radix_number(&mut self, radix: u32) -> tokens::Token {
+        let (start, line, column) = (self.offset, self.line, self.column);
+        self.read_char();
+        self.read_char();
+        let digits_start = self.offset;
+        while is_xid_continue(self.ch) {
+            self.read_char();
+        }
+        self.back_char();
+        let span = tokens::Span::new(start, self.offset, line, column);
+
+        let text = &self.input[digits_start..self.offset];
+        let digits: String = text.strip_suffix("i64").unwrap_or(text).chars().filter(|&c| c != '_').collect();
+        let name = match radix {
+            2 => "a binary",
+            8 => "an octal",
+            _ => "a hexadecimal",
+        };
+        if digits.is_empty() {
+            let prefix = self.input[start..digits_start].to_string();
+            self.invalid_number(span, format!("no digits after '{}'", prefix));
+        } else if let Some(c) = digits.chars().find(|c| !c.is_digit(radix)) {
+            self.invalid_number(span, format!("'{}' is not a {} digit", c, name));
+        } else {
+            match i64::from_str_radix(&digits, radix) {
+                Ok(i) => return tokens::Token::Integer(i),
+                Err(_) => self.out_of_range(span, "int64"),
+            }
+        }
+        tokens::Token::Integer(0)
     }
 
-    // read a number and return it
+    // read a number, the first digit is the current character. Digits can be
+    // split up with '_', a fraction or exponent makes it a float and an `i64`
+    // or `f64` suffix gives its type
     fn read_number(&mut self) -> tokens::Token {
-        // read while digit or decimal point, if decimal point, read until digit
-        // then return float
-        let mut s = String::new();
-        while self.ch.is_digit(10) {
-            s.push(self.ch);
+        match (self.ch, self.peek_char()) {
+            ('0', 'x') => return self.read_radix_number(16),
+            ('0', 'b') => return self.read_radix_number(2),
+            ('0', 'o') => return self.read_radix_number(8),
+            _ => (),
+        }
+        let (start, line, column) = (self.offset, self.line, self.column);
+        let digits = |c: char| c.is_ascii_digit() || c == '_';
+        let mut float = false;
+        while digits(self.ch) {
             self.read_char();
         }
-        if self.ch == '.' {
-            s.push(self.ch);
+        // `1.` is left as `1` and `.`
+        if self.ch == '.' && self.peek_char().is_ascii_digit() {
+            float = true;
             self.read_char();
-            while self.ch.is_digit(10) {
-                s.push(self.ch);
+            while digits(self.ch) {
                 self.read_char();
             }
-            self.back_char();
-            tokens::Token::Float(s.parse().unwrap())
-        } else {
-            self.back_char();
-            tokens::Token::Integer(s.parse().unwrap())
+        }
+        if self.ch == 'e' || self.ch == 'E' {
+            let rest = &self.input[self.read_offset..];
+            let rest = rest.strip_prefix(['+', '-']).unwrap_or(rest);
+            if rest.starts_with(|c: char| c.is_ascii_digit()) {
+                float = true;
+                self.read_char();
+                if self.ch == '+' || self.ch == '-' {
+                    self.read_char();
+                }
+                while digits(self.ch) {
+                    self.read_char();
+                }
+            }
+        }
+        let suffix_start = self.offset;
+        while is_xid_continue(self.ch) {
+            self.read_char();
+        }
+        self.back_char();
+        let span = tokens::Span::new(start, self.offset, line, column);
+
+        let text: String = self.input[start..suffix_start].chars().filter(|&c| c != '_').collect();
+        let suffix = self.input[suffix_start..self.offset].to_string();
+        match suffix.as_str() {
+            "" | "i64" if !float => match text.parse() {
+                Ok(i) => tokens::Token::Integer(i),
+                Err(_) => {
+                    self.out_of_range(span, "int64");
+                    tokens::Token::Integer(0)
+                }
+            },
+            "" | "f64" => match text.parse::<f64>() {
+                Ok(f) if f.is_finite() => tokens::Token::Float(f),
+                _ => {
+                    self.out_of_range(span, "float64");
+                    tokens::Token::Float(0.0)
+                }
+            },
+            "i64" => {
+                self.invalid_number(span, "a float cannot have the suffix 'i64'".to_string());
+                tokens::Token::Float(0.0)
+            }
+            "e" | "E" => {
+                self.invalid_number(span, "the exponent has no digits".to_string());
+                tokens::Token::Float(0.0)
+            }
+            _ => {
+                self.invalid_number(span, format!("unknown suffix '{}'", suffix));
+                tokens::Token::Integer(0)
+            }
         }
     }
 
-    fn read_string(&mut self) -> tokens::Token {
+    // the span of `input[start..end]`, for problems inside tokens that can
+    // span lines
+    fn span_at(&self, start: usize, end: usize) -> tokens::Span {
+        let line_start = self.input[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line = self.input[..start].matches('\n').count() + 1;
+        tokens::Span::new(start, end, line, self.input[line_start..start].chars().count() + 1)
+    }
+
+    // report a string opened by `opening` at `start` that never closes, then
+    // leave the last character of the line or input as the current one
+    fn unterminated(&mut self, start: usize, opening: &str, quotes: &str) {
+        let span = self.span_at(start, start + opening.len());
+        let d = Diagnostic::error("E0003", "Unterminated string".to_string(), span)
+            .with_label("string starts here".to_string())
+            .with_help(format!("close it with '{}'", quotes));
+        self.errors.push(d);
+        self.back_char();
+    }
+
+    // the text of `input[start..end]` with its escape sequences replaced
+    fn unescape(&mut self, start: usize, end: usize) -> String {
         let mut s = String::new();
+        let mut chars = self.input[start..end].char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if c != '\\' {
+                s.push(c);
+                continue;
+            }
+            let (j, e) = match chars.next() {
+                Some(next) => next,
+                // only in an unterminated string, which is reported already
+                None => break,
+            };
+            let escape = match e {
+                'n' => Some('\n'),
+                't' => Some('\t'),
+                'r' => Some('\r'),
+                '0' => Some('\0'),
+                '\\' => Some('\\'),
+                '"' => Some('"'),
+                '{' => Some('{'),
+                '}' => Some('}'),
+                'u' => {
+                    // \u{...} with 1 to 6 hex digits
+                    let mut digits = String::new();
+                    let mut closed = false;
+                    if chars.next_if(|&(_, c)| c == '{').is_some() {
+                        while let Some((_, c)) = chars.next_if(|&(_, c)| c.is_ascii_hexdigit() || c == '}') {
+                            if c == '}' {
+                                closed = true;
+                                break;
+                            }
+                            digits.push(c);
+                        }
+                    }
+                    let end = chars.peek().map(|&(k, _)| start + k).unwrap_or(end);
+                    let span = self.span_at(start + i, end);
+                    let value = u32::from_str_radix(&digits, 16).ok().filter(|_| closed && digits.len() <= 6);
+                    match value.map(char::from_u32) {
+                        Some(Some(c)) => Some(c),
+                        Some(None) => {
+                            let d = Diagnostic::error("E0005", format!("Invalid unicode escape '\\u{{{}}}'", digits), span)
+                                .with_label("not a unicode scalar value".to_string());
+                            self.errors.push(d);
+                            None
+                        }
+                        None => {
+                            let d = Diagnostic::error("E0005", "Invalid unicode escape".to_string(), span)
+                                .with_label("not a valid escape".to_string())
+                                .with_help("write it as '\\u{...}' with 1 to 6 hex digits".to_string());
+                            self.errors.push(d);
+                            None
+                        }
+                    }
+                }
+                other => {
+                    let (message, end) = match other {
+                        '\n' => ("Unknown escape sequence '\\' at the end of a line".to_string(), start + j),
+                        _ => (format!("Unknown escape sequence '\\{}'", other), start + j + other.len_utf8()),
+                    };
+                    let span = self.span_at(start + i, end);
+                    let d = Diagnostic::error("E0004", message, span)
+                        .with_label("not a valid escape".to_string())
+                        .with_help("the escapes are \\n, \\t, \\r, \\0, \\\\, \\\", \\{, \\} and \\u{...}".to_string());
+                    self.errors.push(d);
+                    None
+                }
+            };
+            s.extend(escape);
+        }
+        s
+    }
+
+    // step over an escape sequence in a string, the '\\' is the current
+    // character, so an escaped quote or brace or the braces of `\\u{...}` are
+    // kept as text. Stops at the end of the line or input
+    fn skip_escape(&mut self) {
         self.read_char();
+        if self.ch == 'u' && self.input[self.read_offset..].starts_with('{') {
+            self.read_char();
+            self.read_char();
+            while self.ch.is_ascii_hexdigit() {
+                self.read_char();
+            }
+            if self.ch == '}' {
+                self.read_char();
+            }
+        } else if self.ch != '\0' && self.ch != '\n' {
+            self.read_char();
+        }
+    }
+
+    // read the tokens of an expression in braces inside a string, the '{' is
+    // the current character. Leaves the character after the '}' current, or
+    // returns None at the end of the input, or of the line unless `multiline`
+    fn read_code(&mut self, multiline: bool) -> Option<Vec<tokens::Lexeme>> {
+        self.read_char();
+        let mut code = Vec::new();
+        let mut depth = 0;
+        loop {
+            match self.ch {
+                '\0' => return None,
+                '\n' if !multiline => return None,
+                '}' if depth == 0 => break,
+                _ => (),
+            }
+            let lexeme = self.next_lexeme();
+            match lexeme.token {
+                tokens::Token::LeftBrace => depth += 1,
+                tokens::Token::RightBrace => depth -= 1,
+                _ => (),
+            }
+            code.push(lexeme);
+        }
+        let brace = tokens::Span::new(self.offset, self.offset + 1, self.line, self.column);
+        code.push(tokens::Lexeme::new(tokens::Token::RightBrace, brace));
+        code.push(tokens::Lexeme::new(tokens::Token::Eof, brace.after()));
+        self.read_char();
+        Some(self.strip_whitespace(code))
+    }
+
+    // the token for a string read as `pieces`, a plain string unless it
+    // embeds code
+    fn string(&mut self, pieces: Vec<Piece>) -> tokens::Token {
+        let mut parts = Vec::new();
+        for piece in pieces {
+            match piece {
+                Piece::Text(from, to) => {
+                    let text = self.unescape(from, to);
+                    match parts.last_mut() {
+                        Some(tokens::StringPart::Text(s)) => s.push_str(&text),
+                        _ if text.is_empty() => (),
+                        _ => parts.push(tokens::StringPart::Text(text)),
+                    }
+                }
+                Piece::Code(code) => parts.push(tokens::StringPart::Code(code)),
+            }
+        }
+        match parts.as_slice() {
+            [] => tokens::Token::String(String::new()),
+            [tokens::StringPart::Text(s)] => tokens::Token::String(s.clone()),
+            _ => tokens::Token::Interpolated(parts),
+        }
+    }
+
+    // read a string, the opening '"' is the current character. It cannot
+    // span lines, `"""` strings can
+    fn read_string(&mut self) -> tokens::Token {
+        let start = self.offset;
+        if self.input[start..].starts_with("\"\"\"") {
+            return self.read_multiline_string();
+        }
+        self.read_char();
+        let mut pieces = Vec::new();
+        let mut text = self.offset;
+        while self.ch != '"' {
+            match self.ch {
+                '\0' | '\n' => {
+                    self.unterminated(start, "\"", "\"");
+                    pieces.push(Piece::Text(text, self.read_offset));
+                    return self.string(pieces);
+                }
+                '\\' => self.skip_escape(),
+                '{' => {
+                    pieces.push(Piece::Text(text, self.offset));
+                    match self.read_code(false) {
+                        Some(code) => pieces.push(Piece::Code(code)),
+                        None => {
+                            self.unterminated(start, "\"", "\"");
+                            return self.string(pieces);
+                        }
+                    }
+                    text = self.offset;
+                }
+                _ => self.read_char(),
+            }
+        }
+        pieces.push(Piece::Text(text, self.offset));
+        self.string(pieces)
+    }
+
+    // read an `r"..."` string, which has no escapes and can span lines. The
+    // 'r' is the current character
+    fn read_raw_string(&mut self) -> tokens::Token {
+        let start = self.offset;
+        self.read_char();
+        self.read_char();
+        let body = self.offset;
         while self.ch != '"' {
-            s.push(self.ch);
+            if self.ch == '\0' {
+                self.unterminated(start, "r\"", "\"");
+                return tokens::Token::String(self.input[body..self.read_offset].to_string());
+            }
             self.read_char();
         }
-        tokens::Token::String(s)
+        tokens::Token::String(self.input[body..self.offset].to_string())
     }
 
+    // read a `"""` string. A line break straight after the opening quotes,
+    // a last line holding only the closing quotes' indentation and the
+    // indentation every other line shares are dropped, so the text can be
+    // indented along with the code around it
+    fn read_multiline_string(&mut self) -> tokens::Token {
+        let start = self.offset;
+        self.read_char();
+        self.read_char();
+        self.read_char();
+        let body = self.offset;
+        let mut pieces = Vec::new();
+        let mut text = body;
+        let end = loop {
+            match self.ch {
+                '\0' => {
+                    self.unterminated(start, "\"\"\"", "\"\"\"");
+                    break self.read_offset;
+                }
+                '"' if self.input[self.offset..].starts_with("\"\"\"") => {
+                    let end = self.offset;
+                    self.read_char();
+                    self.read_char();
+                    break end;
+                }
+                '\\' => {
+                    self.skip_escape();
+                    continue;
+                }
+                '{' => {
+                    pieces.push(Piece::Text(text, self.offset));
+                    match self.read_code(true) {
+                        Some(code) => pieces.push(Piece::Code(code)),
+                        None => {
+                            self.unterminated(start, "\"\"\"", "\"\"\"");
+                            return self.string(pieces);
+                        }
+                    }
+                    text = self.offset;
+                    continue;
+                }
+                _ => (),
+            }
+            self.read_char();
+        };
+        pieces.push(Piece::Text(text, end));
+
+        // byte offset of the start and end of every line of the text
+        let mut lines: Vec<(usize, usize)> = Vec::new();
+        let mut line = body;
+        for (i, _) in self.input[body..end].match_indices('\n') {
+            lines.push((line, body + i));
+            line = body + i + 1;
+        }
+        lines.push((line, end));
+
+        let blank = |&(from, to): &(usize, usize)| self.input[from..to].trim().is_empty();
+        if lines.len() > 1 && blank(&lines[0]) {
+            lines.remove(0);
+        }
+        if lines.len() > 1 && blank(lines.last().unwrap()) {
+            lines.pop();
+        }
+        let leading = |from: usize, to: usize| self.input[from..to].len() - self.input[from..to].trim_start_matches([' ', '\t']).len();
+        let indent = lines.iter().filter(|line| !blank(line)).map(|&(from, to)| leading(from, to)).min().unwrap_or(0);
+        let (first, last) = (lines[0].0, lines[lines.len() - 1].1);
+
+        // cut the text down to the kept lines, without their indentation
+        let mut kept = Vec::new();
+        for piece in pieces {
+            let (from, to) = match piece {
+                Piece::Text(from, to) => (from.max(first), to.min(last)),
+                code => {
+                    kept.push(code);
+                    continue;
+                }
+            };
+            let mut at = from;
+            while at < to {
+                let line_end = self.input[at..to].find('\n').map(|i| at + i + 1).unwrap_or(to);
+                let line_start = at == first || self.input[..at].ends_with('\n');
+                let skip = if line_start { indent.min(leading(at, line_end)) } else { 0 };
+                kept.push(Piece::Text(at + skip, line_end));
+                at = line_end;
+            }
+        }
+        self.string(kept)
+    }
+
+    // read an identifier or keyword: a letter or '_' and then any letters,
+    // digits, '_' or combining marks, as Unicode defines them for identifiers
     fn read_identifier(&mut self) -> tokens::Token {
-        let mut s = String::new();
-        while self.ch.is_alphanumeric() {
-            s.push(self.ch);
+        let start = self.offset;
+        while is_xid_continue(self.ch) {
+            self.read_char();
+        }
+        self.back_char();
+        let s = &self.input[start..self.offset];
+        match KEYWORDS.iter().find(|(keyword, _)| *keyword == s) {
+            Some((_, token)) => token.clone(),
+            None => tokens::Token::Identifier(s.to_string()),
+        }
+    }
+
+    // read the rest of a `//` comment, the second '/' is the current character
+    fn read_line_comment(&mut self) -> tokens::Token {
+        let start = self.offset - 1;
+        while self.ch != '\n' && self.ch != '\0' {
             self.read_char();
         }
         self.back_char();
-        match s.as_str() {
-            "print" => tokens::Token::Print,
-            "true" => tokens::Token::Bool(true),
-            "false" => tokens::Token::Bool(false),
-            "let" => tokens::Token::Let,
-            "if" => tokens::Token::If,
-            "else" => tokens::Token::Else,
-            "while" => tokens::Token::While,
-            _ => tokens::Token::Identifier(s),
+
+        let text = self.input[start..self.read_offset].trim_end();
+        // four or more slashes are an ordinary comment, as in rust
+        match text.strip_prefix("///") {
+            Some(doc) if !doc.starts_with('/') => {
+                let doc = doc.strip_prefix(' ').unwrap_or(doc);
+                tokens::Token::DocComment(doc.to_string())
+            }
+            _ => tokens::Token::Comment(text.to_string()),
+        }
+    }
+
+    // read the rest of a `/* */` comment, which can nest and span lines. The
+    // '*' is the current character
+    fn read_block_comment(&mut self) -> tokens::Token {
+        let start = self.offset - 1;
+        let opening = tokens::Span::new(start, start + 2, self.line, self.column - 1);
+        let mut depth = 1;
+        self.read_char();
+        loop {
+            let next = self.input.get(self.read_offset..).and_then(|rest| rest.chars().next());
+            match (self.ch, next) {
+                ('\0', _) => {
+                    let d = Diagnostic::error("E0002", "Unterminated block comment".to_string(), opening)
+                        .with_label("comment starts here".to_string())
+                        .with_help("close it with '*/'".to_string());
+                    self.errors.push(d);
+                    self.back_char();
+                    break;
+                }
+                ('/', Some('*')) => {
+                    depth += 1;
+                    self.read_char();
+                }
+                ('*', Some('/')) => {
+                    depth -= 1;
+                    self.read_char();
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => (),
+            }
+            self.read_char();
         }
+        tokens::Token::Comment(self.input[start..self.read_offset].to_string())
     }
 
     fn match_token(&mut self) -> tokens::Token {
@@ -99,16 +592,55 @@ impl Lexer {
             '(' => tokens::Token::LeftParen,
             ')' => tokens::Token::RightParen,
             '+' => tokens::Token::Plus,
-            '-' => tokens::Token::Minus,
+            '-' => {
+                self.read_char();
+                if self.ch == '>' {
+                    tokens::Token::Arrow
+                } else {
+                    self.back_char();
+                    tokens::Token::Minus
+                }
+            }
             '*' => tokens::Token::Times,
-            '/' => tokens::Token::Divide,
+            '/' => {
+                self.read_char();
+                match self.ch {
+                    '/' => self.read_line_comment(),
+                    '*' => self.read_block_comment(),
+                    _ => {
+                        self.back_char();
+                        tokens::Token::Divide
+                    }
+                }
+            }
             '.' => tokens::Token::Dot,
             ';' => tokens::Token::SemiColon,
             '\'' => tokens::Token::Quote,
             '"' => self.read_string(),
             '{' => tokens::Token::LeftBrace,
             '}' => tokens::Token::RightBrace,
-            ':' => tokens::Token::Colon,
+            '[' => tokens::Token::LeftBracket,
+            ']' => tokens::Token::RightBracket,
+            ':' => {
+                self.read_char();
+                if self.ch == ':' {
+                    tokens::Token::DoubleColon
+                } else {
+                    self.back_char();
+                    tokens::Token::Colon
+                }
+            }
+            '?' => {
+                self.read_char();
+                match self.ch {
+                    '?' => tokens::Token::DoubleQuestion,
+                    '.' => tokens::Token::QuestionDot,
+                    _ => {
+                        self.back_char();
+                        tokens::Token::Question
+                    }
+                }
+            }
 
             // whitespace
             ' ' => tokens::Token::WhiteSpace,
@@ -120,7 +652,8 @@ impl Lexer {
                 if self.ch == '|' {
                     tokens::Token::Or
                 } else {
-                    panic!("Unknown token: |{}", self.ch);
+                    self.back_char();
+                    tokens::Token::Illegal('|')
                 }
             },
 
@@ -129,7 +662,8 @@ impl Lexer {
                 if self.ch == '&' {
                     tokens::Token::And
                 } else {
-                    panic!("Unknown token: &{}", self.ch);
+                    self.back_char();
+                    tokens::Token::Illegal('&')
                 }
             },
 
@@ -141,6 +675,8 @@ impl Lexer {
                 self.read_char();
                 if self.ch == '=' {
                     tokens::Token::DoubleEqual
+                } else if self.ch == '>' {
+                    tokens::Token::FatArrow
                 } else {
                     self.back_char();
                     tokens::Token::Equal
@@ -180,46 +716,83 @@ impl Lexer {
             // numbers
             '0'..='9' => self.read_number(),
 
+            'r' if self.input[self.read_offset..].starts_with('"') => self.read_raw_string(),
+
             // identifiers
-            'a'..='z' => self.read_identifier(),
+            c if c == '_' || is_xid_start(c) => self.read_identifier(),
 
             // line
-            '\n' => {
-                self.line += 1;
-                tokens::Token::NewLine
-            }
+            '\n' => tokens::Token::NewLine,
 
             '\0' => tokens::Token::Eof,
 
             // illegal
-            _ => tokens::Token::Illegal(self.ch, self.position, self.line),
+            _ => tokens::Token::Illegal(self.ch),
         }
     }
 
-    fn strip_whitespace(&mut self, orig_tokens: Vec<tokens::Token>) -> Vec<tokens::Token> {
+    // lex a single token starting at the current character, recording its span
+    fn next_lexeme(&mut self) -> tokens::Lexeme {
+        let start = self.offset;
+        let (line, column) = (self.line, self.column);
+
+        let token = self.match_token();
+        let end = if token == tokens::Token::Eof {
+            start
+        } else {
+            // the token ends wherever the next one starts
+            self.read_char();
+            self.offset
+        };
+        let span = tokens::Span::new(start, end, line, column);
+        if let tokens::Token::Illegal(c) = token {
+            let mut d = Diagnostic::error("E0001", format!("Unknown character '{}'", c), span)
+                .with_label("not valid here".to_string());
+            if c == '|' || c == '&' {
+                d = d.with_help(format!("did you mean '{}{}'?", c, c));
+            }
+            self.errors.push(d);
+        }
+        tokens::Lexeme::new(token, span)
+    }
+
+    // problems found by the last call to `lex`
+    pub fn errors(&self) -> &[Diagnostic] {
+        &self.errors
+    }
+
+    // drop whitespace and move comments onto the trivia of the token after
+    // them
+    fn strip_whitespace(&mut self, orig_tokens: Vec<tokens::Lexeme>) -> Vec<tokens::Lexeme> {
         let mut tokens = Vec::new();
-        for token in orig_tokens {
-            match token {
+        let mut trivia = Vec::new();
+        for mut lexeme in orig_tokens {
+            match lexeme.token {
                 tokens::Token::WhiteSpace => (),
                 tokens::Token::NewLine => (),
-                _ => tokens.push(token),
+                tokens::Token::Comment(_) | tokens::Token::DocComment(_) => trivia.push(lexeme),
+                _ => {
+                    lexeme.trivia = std::mem::take(&mut trivia);
+                    tokens.push(lexeme);
+                }
             }
         }
         tokens
     }
 
     // lexes the input string
-    pub fn lex(&mut self, debug: bool) -> Vec<tokens::Token> {
+    pub fn lex(&mut self, debug: bool) -> Vec<tokens::Lexeme> {
         let mut tokens = Vec::new();
         while self.ch != '\0' {
-            tokens.push(self.match_token());
-            self.read_char();
+            tokens.push(self.next_lexeme());
         }
-        tokens.push(self.match_token());
+        tokens.push(self.next_lexeme());
 
+        let tokens = self.strip_whitespace(tokens);
         if debug {
-            println!("Tokens: {:?}", self.strip_whitespace(tokens.clone()));
+            let kinds: Vec<&tokens::Token> = tokens.iter().map(|l| &l.token).collect();
+            println!("Tokens: {:?}", kinds);
         }
-        self.strip_whitespace(tokens)
+        tokens
     }
 }
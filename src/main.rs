@@ -1,5 +1,7 @@
-use std::io::*;
-use std::fs::File;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+use std::thread;
 
 mod tokens;
 mod lexer;
@@ -8,46 +10,346 @@ mod ast;
 mod typechecker;
 mod eval;
 mod environment;
+mod diagnostics;
+mod resolver;
+mod compiler;
+mod vm;
+mod repl;
+mod formatter;
 
-fn read_file(fname: String) -> String {
-    let mut f = File::open(fname).unwrap();
-    let mut s = String::new();
-    f.read_to_string(&mut s).unwrap();
-    s
+// process exit codes
+const EXIT_RUNTIME_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_SYNTAX_ERROR: i32 = 3;
+const EXIT_TYPE_ERROR: i32 = 4;
+// `fmt --check` found a file that is not formatted
+const EXIT_NOT_FORMATTED: i32 = 1;
+// the formatted program would not parse back into the same one
+const EXIT_FORMAT_FAILED: i32 = 1;
+
+// the most Rust stack one call in the tree-walker takes, a few times what a
+// debug build measures for a call made from inside a `match` arm
+const CALL_STACK_SIZE: usize = 64 * 1024;
+// programs run on a thread with room for `eval::MAX_CALL_DEPTH` nested calls
+const STACK_SIZE: usize = eval::MAX_CALL_DEPTH * CALL_STACK_SIZE;
+
+const USAGE: &str = "\
+Usage: mt [command] [options] [file]
+
+Commands:
+    run <file>      run a program, the default when only a file is given
+    check <file>    lex, parse and typecheck a program without running it
+    lex <file>      print the tokens of a program
+    parse <file>    print the syntax tree of a program
+    fmt <file>      format a file in place, or print the formatted program
+                    when it comes from '-e' or stdin
+    repl            start the interactive prompt, the default with no arguments
+
+Options:
+    -e <code>       use <code> as the program instead of a file
+    -               read the program from stdin
+    --vm            run on the bytecode vm instead of the tree-walker
+    --check         with fmt, only report whether the program is formatted
+    -d, --debug     print the tokens of everything lexed
+    -h, --help      show this message
+
+Exit codes: 0 success, 1 runtime error, unformatted file or failed formatting,
+2 usage or input error, 3 syntax error, 4 name or type error";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Command {
+    Run,
+    Check,
+    Lex,
+    Parse,
+    Fmt,
+    Repl,
 }
 
-fn repl(debug: bool) {
-    let mut history = Vec::new();
-    loop {
-        print!("mt -> ");
-        std::io::stdout().flush().unwrap();
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
+impl Command {
+    fn name(self) -> &'static str {
+        match self {
+            Command::Run => "run",
+            Command::Check => "check",
+            Command::Lex => "lex",
+            Command::Parse => "parse",
+            Command::Fmt => "fmt",
+            Command::Repl => "repl",
+        }
+    }
+}
 
-        let mut l = lexer::Lexer::new(input.clone());
-        let mut p = parser::Parser::new(l.lex(debug));
-        let ast = p.parse();
-        let mut e = eval::Evaluator::new();
-        e.eval(ast);
-        history.push(input);
-    }   
+// where the program comes from
+#[derive(Debug, PartialEq, Clone)]
+enum Input {
+    File(String),
+    Stdin,
+    Inline(String),
 }
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() == 1 {
-        repl(false);
-    } else if args.len() == 2 {
-        let fname = args[1].clone();
-        let mut l = lexer::Lexer::new(read_file(fname));
-        let mut p = parser::Parser::new(l.lex(false));
-        let ast = p.parse();
-        let type_map = p.get_type_map();
-        // println!("Typechecking... got {:?}", typechecker::typecheck(&ast));
-        typechecker::typecheck(&ast, type_map.clone());
-        let mut e = eval::Evaluator::new();
-        e.eval(ast);
+#[derive(Debug, PartialEq, Clone)]
+struct Options {
+    command: Command,
+    input: Option<Input>,
+    use_vm: bool,
+    check: bool,
+    debug: bool,
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut command = None;
+    let mut input = None;
+    let mut use_vm = false;
+    let mut check = false;
+    let mut debug = false;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let next = match arg.as_str() {
+            "--vm" => {
+                use_vm = true;
+                continue;
+            }
+            "--check" => {
+                check = true;
+                continue;
+            }
+            "-d" | "--debug" => {
+                debug = true;
+                continue;
+            }
+            "-e" => match args.next() {
+                Some(code) => Input::Inline(code),
+                None => return Err("'-e' needs some code after it".to_string()),
+            },
+            "-" => Input::Stdin,
+            flag if flag.starts_with('-') => return Err(format!("Unknown option '{}'", flag)),
+            name if command.is_none() && input.is_none() => {
+                command = match name {
+                    "run" => Some(Command::Run),
+                    "check" => Some(Command::Check),
+                    "lex" => Some(Command::Lex),
+                    "parse" => Some(Command::Parse),
+                    "fmt" => Some(Command::Fmt),
+                    "repl" => Some(Command::Repl),
+                    _ => None,
+                };
+                if command.is_some() {
+                    continue;
+                }
+                Input::File(arg)
+            }
+            _ => Input::File(arg),
+        };
+        if input.is_some() {
+            return Err("Only one program can be given".to_string());
+        }
+        input = Some(next);
+    }
+
+    let command = match (command, &input) {
+        (Some(Command::Repl), Some(_)) => return Err("'repl' does not take a program".to_string()),
+        (Some(Command::Repl), None) | (None, None) => Command::Repl,
+        (Some(command), None) => return Err(format!("'{}' needs a file, '-e <code>' or '-'", command.name())),
+        (Some(command), Some(_)) => command,
+        (None, Some(_)) => Command::Run,
+    };
+    if check && command != Command::Fmt {
+        return Err("'--check' only works with 'fmt'".to_string());
+    }
+    Ok(Options { command, input, use_vm, check, debug })
+}
+
+// the name to report errors against and the source of a program
+fn read_input(input: &Input) -> Result<(String, String), i32> {
+    let result = match input {
+        Input::File(fname) => fs::read_to_string(fname).map(|s| (fname.clone(), s)),
+        Input::Stdin => {
+            let mut s = String::new();
+            io::stdin().read_to_string(&mut s).map(|_| ("<stdin>".to_string(), s))
+        }
+        Input::Inline(code) => Ok(("<-e>".to_string(), code.clone())),
+    };
+    result.map_err(|err| {
+        let name = match input {
+            Input::File(fname) => fname.as_str(),
+            _ => "<stdin>",
+        };
+        eprintln!("Cannot read {}: {}", name, err);
+        EXIT_USAGE
+    })
+}
+
+fn lex(source: &str, fname: &str, debug: bool) -> Result<Vec<tokens::Lexeme>, i32> {
+    let mut l = lexer::Lexer::new(source.to_string());
+    let tokens = l.lex(debug);
+    if !l.errors().is_empty() {
+        diagnostics::report(l.errors(), fname, source);
+        return Err(EXIT_SYNTAX_ERROR);
+    }
+    Ok(tokens)
+}
+
+// one line per token and comment, the tokens of the expressions in an
+// interpolated string indented under it
+fn print_lexemes(lexemes: &[tokens::Lexeme], depth: usize) {
+    let indent = "  ".repeat(depth);
+    for lexeme in lexemes {
+        for l in lexeme.trivia.iter().chain([lexeme]) {
+            let at = format!("{}:{}", l.span.line, l.span.column);
+            match &l.token {
+                tokens::Token::Interpolated(parts) => {
+                    println!("{}\t{}Interpolated", at, indent);
+                    for part in parts {
+                        match part {
+                            tokens::StringPart::Text(s) => println!("{}\t{}  Text({:?})", at, indent, s),
+                            tokens::StringPart::Code(code) => print_lexemes(code, depth + 1),
+                        }
+                    }
+                }
+                token => println!("{}\t{}{:?}", at, indent, token),
+            }
+        }
+    }
+}
+
+fn parse(source: &str, fname: &str, debug: bool) -> Result<Vec<ast::Statement>, i32> {
+    parse_tokens(lex(source, fname, debug)?, source, fname)
+}
+
+fn parse_tokens(tokens: Vec<tokens::Lexeme>, source: &str, fname: &str) -> Result<Vec<ast::Statement>, i32> {
+    parser::Parser::new(tokens).parse().map_err(|errors| {
+        let errors: Vec<_> = errors.iter().map(|e| e.to_diagnostic()).collect();
+        diagnostics::report(&errors, fname, source);
+        EXIT_SYNTAX_ERROR
+    })
+}
+
+// everything short of running: lex, parse, resolve and typecheck
+fn check(source: &str, fname: &str, debug: bool) -> Result<Vec<ast::Statement>, i32> {
+    let ast = parse(source, fname, debug)?;
+    if let Err(errors) = resolver::resolve(&ast) {
+        let errors: Vec<_> = errors.iter().map(|e| e.to_diagnostic()).collect();
+        diagnostics::report(&errors, fname, source);
+        return Err(EXIT_TYPE_ERROR);
+    }
+    if let Err(errors) = typechecker::typecheck(&ast) {
+        let errors: Vec<_> = errors.iter().map(|e| e.to_diagnostic()).collect();
+        diagnostics::report(&errors, fname, source);
+        return Err(EXIT_TYPE_ERROR);
+    }
+    Ok(ast)
+}
+
+// call `f` on a thread with a `STACK_SIZE` stack, for running programs. The
+// tree-walker recurses on the Rust stack for every call
+fn on_large_stack<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    thread::scope(|scope| {
+        let worker = thread::Builder::new().stack_size(STACK_SIZE).spawn_scoped(scope, f);
+        match worker.expect("cannot start the interpreter thread").join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    })
+}
+
+// run with the tree-walker, or compiled to bytecode when `use_vm` is set
+fn run(source: &str, fname: &str, options: &Options) -> Result<(), i32> {
+    let ast = check(source, fname, options.debug)?;
+    let result = if options.use_vm {
+        vm::Vm::new().run(compiler::compile(&ast))
     } else {
-        println!("Usage: mt [filename]");
+        eval::Evaluator::new().eval(ast).map(|_| ())
+    };
+    result.map_err(|err| {
+        diagnostics::report(&[err.to_diagnostic()], fname, source);
+        EXIT_RUNTIME_ERROR
+    })
+}
+
+// rewrite a file in its canonical layout, print it for other inputs, or with
+// `--check` only say whether anything would change
+fn fmt(source: &str, fname: &str, options: &Options) -> Result<(), i32> {
+    let tokens = lex(source, fname, options.debug)?;
+    let comments: Vec<_> = tokens.iter().flat_map(|l| l.trivia.clone()).collect();
+    let ast = parse_tokens(tokens, source, fname)?;
+    let formatted = formatter::format(source, &ast, &comments);
+    if !reparses(&formatted, &ast) {
+        eprintln!("Formatting {} would change what it means, leaving it as it is", fname);
+        return Err(EXIT_FORMAT_FAILED);
+    }
+    if options.check {
+        if formatted != source {
+            eprintln!("{} is not formatted", fname);
+            return Err(EXIT_NOT_FORMATTED);
+        }
+        return Ok(());
+    }
+    match &options.input {
+        Some(Input::File(fname)) if formatted != source => fs::write(fname, formatted).map_err(|err| {
+            eprintln!("Cannot write {}: {}", fname, err);
+            EXIT_USAGE
+        }),
+        Some(Input::File(_)) => Ok(()),
+        _ => {
+            print!("{}", formatted);
+            Ok(())
+        }
+    }
+}
+
+// does `formatted` parse into the same program as `ast`, spans aside
+fn reparses(formatted: &str, ast: &[ast::Statement]) -> bool {
+    let mut l = lexer::Lexer::new(formatted.to_string());
+    let tokens = l.lex(false);
+    if !l.errors().is_empty() {
+        return false;
+    }
+    match parser::Parser::new(tokens).parse() {
+        Ok(reparsed) => ast::outline(&reparsed) == ast::outline(ast),
+        Err(_) => false,
+    }
+}
+
+fn execute(options: &Options) -> Result<(), i32> {
+    let (fname, source) = match &options.input {
+        Some(input) => read_input(input)?,
+        None => {
+            on_large_stack(|| repl::repl(options.debug));
+            return Ok(());
+        }
+    };
+
+    match options.command {
+        Command::Run => on_large_stack(|| run(&source, &fname, options)),
+        Command::Check => check(&source, &fname, options.debug).map(|_| ()),
+        Command::Lex => {
+            print_lexemes(&lex(&source, &fname, options.debug)?, 0);
+            Ok(())
+        }
+        Command::Parse => {
+            print!("{}", ast::outline(&parse(&source, &fname, options.debug)?));
+            Ok(())
+        }
+        Command::Fmt => fmt(&source, &fname, options),
+        Command::Repl => unreachable!("the repl takes no input"),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+    if let Err(code) = execute(&options) {
+        process::exit(code);
     }
 }
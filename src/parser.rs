@@ -1,148 +1,461 @@
-use std::collections::HashMap;
+use std::rc::Rc;
 
-use crate::tokens::Token;
-use crate::ast::Expression;
-use crate::ast::Statement;
-use anyhow::Result;
+use crate::tokens::{Lexeme, Span, StringPart, Token};
+use crate::ast::{
+    Arm, Enum, Expression, ExpressionKind, Fragment, Function, Parameter, Pattern, PatternKind, Statement,
+    StatementKind, Struct, TypeAnnotation, Variable, Variant,
+};
+use crate::diagnostics::Diagnostic;
+
+type Result<T> = std::result::Result<T, ParseError>;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ParseErrorKind {
+    Expected(Token),
+    ExpectedExpression,
+    ExpectedIdentifier,
+    MissingInitialiser,
+    InvalidAssignmentTarget,
+    // a struct, enum or impl block inside a block or function
+    MisplacedDeclaration,
+    MissingSelf,
+    ExpectedPattern,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub message: String,
+    pub span: Span,
+    // the token the parser was looking at when it gave up
+    pub found: Token,
+}
+
+impl ParseError {
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let (code, label) = match &self.kind {
+            ParseErrorKind::Expected(t) => ("E0100", format!("expected '{}'", t)),
+            ParseErrorKind::ExpectedExpression => ("E0101", format!("found '{}'", self.found)),
+            ParseErrorKind::ExpectedIdentifier => ("E0102", format!("found '{}'", self.found)),
+            ParseErrorKind::MissingInitialiser => ("E0103", "declared here".to_string()),
+            ParseErrorKind::InvalidAssignmentTarget => ("E0104", "cannot be assigned to".to_string()),
+            ParseErrorKind::MisplacedDeclaration => ("E0105", "not at the top level".to_string()),
+            ParseErrorKind::MissingSelf => ("E0106", "expected 'self'".to_string()),
+            ParseErrorKind::ExpectedPattern => ("E0107", format!("found '{}'", self.found)),
+        };
+        let d = Diagnostic::error(code, self.message.clone(), self.span).with_label(label);
+        match self.kind {
+            ParseErrorKind::MissingInitialiser => d.with_help("give the variable a value with '= ...'".to_string()),
+            ParseErrorKind::MisplacedDeclaration => d.with_help("move it out to the top of the file".to_string()),
+            ParseErrorKind::MissingSelf => d.with_help("write the method as 'fn name(self, ...)'".to_string()),
+            _ => d,
+        }
+    }
+}
 
 // global parser object
 pub struct Parser {
-    type_map: HashMap<String, String>,
-    tokens: Vec<Token>,
+    tokens: Vec<Lexeme>,
     pos: usize,
+    errors: Vec<ParseError>,
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Parser {
+    pub fn new(tokens: Vec<Lexeme>) -> Parser {
         Parser {
-            type_map: HashMap::new(),
-            tokens: tokens,
+            tokens,
             pos: 0,
+            errors: Vec::new(),
         }
     }
 
-    pub fn get_type_map(&self) -> &HashMap<String, String> {
-        &self.type_map
-    }
-
-    pub fn parse(&mut self) -> Vec<Statement> {
+    // parse the whole token stream, collecting every syntax error on the way
+    pub fn parse(&mut self) -> std::result::Result<Vec<Statement>, Vec<ParseError>> {
         let mut ast = Vec::new();
-
-        loop {
-            // try parse
-            if let Some(stmt) = self.declaration() {
+        while !self.done() {
+            let start = self.pos;
+            let result = self.item();
+            if let Some(stmt) = self.recover(result) {
                 ast.push(stmt);
+            } else if self.pos == start {
+                // nothing consumed (e.g. a stray '}'), skip it to make progress
+                self.advance();
             }
+        }
 
-            if self.done() {
-                break;
+        if self.errors.is_empty() {
+            Ok(ast)
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    // parse a declaration, on failure record the error and skip to the next
+    // statement boundary
+    fn recovering_declaration(&mut self, tail: bool) -> Option<Statement> {
+        let result = self.declaration(tail);
+        self.recover(result)
+    }
+
+    fn recover(&mut self, result: Result<Statement>) -> Option<Statement> {
+        match result {
+            Ok(stmt) => Some(stmt),
+            Err(e) => {
+                self.errors.push(e);
+                self.synchronise();
+                None
             }
         }
-        ast
     }
 
-    fn declaration(&mut self) -> Option<Statement> {
-        if self.expect(&Token::Let) {
-            self.advance();
-            if let Ok(stmt) = self.var_declaration() {
-                return Some(stmt);
-            } else {
-                return None;
+    // discard tokens until just after a ';', or just before a '}' or the start
+    // of a new statement
+    fn synchronise(&mut self) {
+        while !self.done() {
+            match self.peek() {
+                Token::RightBrace | Token::Let | Token::Print | Token::If | Token::While |
+                    Token::Fn | Token::Return | Token::Struct | Token::Impl | Token::Enum => return,
+                Token::SemiColon => {
+                    self.advance();
+                    return;
+                }
+                _ => {
+                    self.advance();
+                }
             }
-        } else if let Ok(statement) = self.statement() {
-            Some(statement)
+        }
+    }
+
+    // a declaration at the top level, the only place structs, enums and impl
+    // blocks can go
+    fn item(&mut self) -> Result<Statement> {
+        let doc = self.doc_comments();
+        let mut stmt = if self.expect(&Token::Struct) {
+            self.struct_declaration()?
+        } else if self.expect(&Token::Enum) {
+            self.enum_declaration()?
+        } else if self.expect(&Token::Impl) {
+            self.impl_block()?
         } else {
-            None
+            return self.declaration(false);
+        };
+        stmt.doc = doc;
+        Ok(stmt)
+    }
+
+    // `tail` is set for statements directly inside a function body, where a
+    // final expression without ';' is the return value
+    fn declaration(&mut self, tail: bool) -> Result<Statement> {
+        if self.expect(&Token::Struct) || self.expect(&Token::Enum) || self.expect(&Token::Impl) {
+            // parsed anyway, so the error does not cascade from its braces
+            let span = self.peek_span();
+            let message = if self.expect(&Token::Struct) {
+                "Structs can only be declared at the top level"
+            } else if self.expect(&Token::Enum) {
+                "Enums can only be declared at the top level"
+            } else {
+                "'impl' blocks can only be at the top level"
+            };
+            let error = self.error(ParseErrorKind::MisplacedDeclaration, message.to_string(), span);
+            let stmt = self.item()?;
+            self.errors.push(error);
+            return Ok(stmt);
         }
+        let doc = self.doc_comments();
+        let mut stmt = if self.expect(&Token::Let) {
+            self.var_declaration()?
+        } else if self.expect(&Token::Fn) && matches!(self.peek_next(), Token::Identifier(_)) {
+            self.function_declaration()?
+        } else {
+            return self.statement(tail);
+        };
+        stmt.doc = doc;
+        Ok(stmt)
+    }
+
+    // the doc comments in front of the next token
+    fn doc_comments(&self) -> Vec<String> {
+        self.tokens[self.pos]
+            .trivia
+            .iter()
+            .filter_map(|trivia| match &trivia.token {
+                Token::DocComment(text) => Some(text.clone()),
+                _ => None,
+            })
+            .collect()
     }
 
     fn var_declaration(&mut self) -> Result<Statement> {
+        let start = self.peek_span();
+        self.advance();
         let name = self.expect_identifier()?;
         self.advance();
-        self.consume(&Token::Colon, "Expected ':' after variable name".to_string());
-        let ty = self.expect_identifier()?;
+        self.consume(&Token::Colon, "Expected ':' after variable name".to_string())?;
+        let ty = self.type_name()?;
 
+        if !self.expect(&Token::Equal) {
+            return Err(self.error(
+                ParseErrorKind::MissingInitialiser,
+                format!("Variable '{}' has no initialiser", name),
+                start.to(self.previous_span()),
+            ));
+        }
         self.advance();
+        let initialiser = self.expression()?;
+
+        self.consume(&Token::SemiColon, "Expected ';' after variable declaration".to_string())?;
+        Ok(Statement::new(
+            StatementKind::Let(Variable::new(name), ty, Box::new(initialiser)),
+            start.to(self.previous_span()),
+        ))
+    }
 
-        let initialiser = if self.expect(&Token::Equal) {
+    fn function_declaration(&mut self) -> Result<Statement> {
+        let start = self.peek_span();
+        self.advance();
+        let name = self.expect_identifier()?;
+        self.advance();
+        let function = self.function(name.clone(), start, None)?;
+        let span = function.span;
+        Ok(Statement::new(StatementKind::Function(Variable::new(name), Rc::new(function)), span))
+    }
+
+    fn struct_declaration(&mut self) -> Result<Statement> {
+        let start = self.peek_span();
+        self.advance();
+        let name = self.expect_identifier()?;
+        self.advance();
+        self.consume(&Token::LeftBrace, format!("Expected '{{' after struct name '{}'", name))?;
+        let mut fields = Vec::new();
+        while !self.expect(&Token::RightBrace) {
+            let span = self.peek_span();
+            let name = self.expect_identifier()?;
+            self.advance();
+            self.consume(&Token::Colon, "Expected ':' after field name".to_string())?;
+            let ty = self.type_name()?;
+            fields.push(Parameter { name, ty, span });
+            if !self.expect(&Token::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        self.consume(&Token::RightBrace, "Expected '}' after struct fields".to_string())?;
+        let span = start.to(self.previous_span());
+        Ok(Statement::new(StatementKind::Struct(Rc::new(Struct { name, fields, span })), span))
+    }
+
+    fn enum_declaration(&mut self) -> Result<Statement> {
+        let start = self.peek_span();
+        self.advance();
+        let name = self.expect_identifier()?;
+        self.advance();
+        self.consume(&Token::LeftBrace, format!("Expected '{{' after enum name '{}'", name))?;
+        let mut variants = Vec::new();
+        while !self.expect(&Token::RightBrace) {
+            let span = self.peek_span();
+            let name = self.expect_identifier()?;
             self.advance();
-            self.expression().ok()
+            let mut fields = Vec::new();
+            if self.expect(&Token::LeftParen) {
+                self.advance();
+                while !self.expect(&Token::RightParen) {
+                    fields.push(self.type_name()?);
+                    if !self.expect(&Token::Comma) {
+                        break;
+                    }
+                    self.advance();
+                }
+                self.consume(&Token::RightParen, "Expected ')' after variant types".to_string())?;
+            }
+            variants.push(Variant { name, fields, span: span.to(self.previous_span()) });
+            if !self.expect(&Token::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        self.consume(&Token::RightBrace, "Expected '}' after enum variants".to_string())?;
+        let span = start.to(self.previous_span());
+        Ok(Statement::new(StatementKind::Enum(Rc::new(Enum { name, variants, span })), span))
+    }
+
+    fn impl_block(&mut self) -> Result<Statement> {
+        let start = self.peek_span();
+        self.advance();
+        let name = self.expect_identifier()?;
+        self.advance();
+        self.consume(&Token::LeftBrace, format!("Expected '{{' after 'impl {}'", name))?;
+        let mut methods = Vec::new();
+        while !self.expect(&Token::RightBrace) && !self.done() {
+            let fn_start = self.peek_span();
+            self.consume(&Token::Fn, "Expected 'fn' or '}' in impl block".to_string())?;
+            let method = self.expect_identifier()?;
+            self.advance();
+            methods.push(Rc::new(self.function(method, fn_start, Some(&name))?));
+        }
+        self.consume(&Token::RightBrace, "Expected '}' after impl block".to_string())?;
+        Ok(Statement::new(StatementKind::Impl(name, methods), start.to(self.previous_span())))
+    }
+
+    // everything after the name: parameters, return type and body. A method
+    // of the struct `owner` takes `self` first, typed as the struct
+    fn function(&mut self, name: String, start: Span, owner: Option<&str>) -> Result<Function> {
+        self.consume(&Token::LeftParen, "Expected '(' after 'fn'".to_string())?;
+        let mut params = Vec::new();
+        if let Some(owner) = owner {
+            // without `self` the rest of the method still parses, so only
+            // this error is reported
+            if self.expect(&Token::SelfValue) {
+                let span = self.peek_span();
+                self.advance();
+                let ty = TypeAnnotation::Named(owner.to_string());
+                params.push(Parameter { name: "self".to_string(), ty, span });
+                if self.expect(&Token::Comma) {
+                    self.advance();
+                }
+            } else {
+                let message = format!("Method '{}' must take 'self' as its first parameter", name);
+                let error = self.error(ParseErrorKind::MissingSelf, message, self.peek_span());
+                self.errors.push(error);
+            }
+        }
+        if !self.expect(&Token::RightParen) {
+            loop {
+                let span = self.peek_span();
+                let name = self.expect_identifier()?;
+                self.advance();
+                self.consume(&Token::Colon, "Expected ':' after parameter name".to_string())?;
+                let ty = self.type_name()?;
+                params.push(Parameter { name, ty, span });
+                if !self.expect(&Token::Comma) {
+                    break;
+                }
+                self.advance();
+            }
+        }
+        self.consume(&Token::RightParen, "Expected ')' after parameters".to_string())?;
+
+        // no return type means the function returns null
+        let return_type = if self.expect(&Token::Arrow) {
+            self.advance();
+            self.type_name()?
         } else {
-            println!("No initialiser");
-            None
+            TypeAnnotation::Named("null".to_string())
         };
 
-        self.consume(&Token::SemiColon, "Expected ';' after variable declaration".to_string());
-        self.type_map.insert(name.clone(), ty.clone());
-        Ok(Statement::Let(name, ty, Box::new(initialiser.unwrap())))
+        let body = self.function_body()?;
+        Ok(Function {
+            name,
+            params,
+            return_type,
+            body,
+            span: start.to(self.previous_span()),
+        })
     }
 
-    fn statement(&mut self) -> Result<Statement> {
+    fn function_body(&mut self) -> Result<Vec<Statement>> {
+        let mut statements = Vec::new();
+        self.consume(&Token::LeftBrace, "Expected '{' before function body".to_string())?;
+        while !self.expect(&Token::RightBrace) && !self.done() {
+            if let Some(stmt) = self.recovering_declaration(true) {
+                statements.push(stmt);
+            }
+        }
+        self.consume(&Token::RightBrace, "Expected '}' after function body".to_string())?;
+        Ok(statements)
+    }
+
+    fn statement(&mut self, tail: bool) -> Result<Statement> {
         if self.expect(&Token::Print) {
             self.print_statement()
         } else if self.expect(&Token::If) {
             self.if_statement()
-        } else if self.expect(&&Token::LeftBrace) {
-            Ok(Statement::Block(self.block()?))
+        } else if self.expect(&Token::LeftBrace) && !self.map_ahead() {
+            let start = self.peek_span();
+            let statements = self.block()?;
+            Ok(Statement::new(StatementKind::Block(statements), start.to(self.previous_span())))
         } else if self.expect(&Token::While) {
             self.while_statement()
+        } else if self.expect(&Token::Return) {
+            self.return_statement()
         } else {
-            self.expression_statement()
+            self.expression_statement(tail)
         }
     }
 
     fn block(&mut self) -> Result<Vec<Statement>> {
         let mut statements = Vec::new();
-        self.advance();
+        self.consume(&Token::LeftBrace, "Expected '{' before block".to_string())?;
         while !self.expect(&Token::RightBrace) && !self.done() {
-            statements.push(self.declaration().unwrap());
+            if let Some(stmt) = self.recovering_declaration(false) {
+                statements.push(stmt);
+            }
         }
-        self.advance();
+        self.consume(&Token::RightBrace, "Expected '}' after block".to_string())?;
         Ok(statements)
-
     }
 
     fn while_statement(&mut self) -> Result<Statement> {
+        let start = self.peek_span();
         self.advance();
-        self.consume(&Token::LeftParen, "Expected '(' after 'while'".to_string());
+        self.consume(&Token::LeftParen, "Expected '(' after 'while'".to_string())?;
         let condition = self.expression()?;
-        self.consume(&Token::RightParen, "Expected ')' after condition".to_string());
-        let body = self.statement()?;
-        Ok(Statement::While(condition, Box::new(body)))
+        self.consume(&Token::RightParen, "Expected ')' after condition".to_string())?;
+        let body = self.statement(false)?;
+        Ok(Statement::new(
+            StatementKind::While(condition, Box::new(body)),
+            start.to(self.previous_span()),
+        ))
     }
 
     fn if_statement(&mut self) -> Result<Statement> {
+        let start = self.peek_span();
         self.advance();
-        self.consume(&Token::LeftParen, "Expected '(' after 'if'".to_string());
+        self.consume(&Token::LeftParen, "Expected '(' after 'if'".to_string())?;
         let condition = self.expression()?;
-        self.consume(&Token::RightParen, "Expected ')' after if condition".to_string());
-        let consequence = self.statement()?;
-        if self.expect(&Token::Else) {
+        self.consume(&Token::RightParen, "Expected ')' after if condition".to_string())?;
+        let consequence = self.statement(false)?;
+        let kind = if self.expect(&Token::Else) {
             self.advance();
-        Ok(Statement::IfElse (
-            Box::new(condition),
-            Box::new(consequence),
-            Box::new(self.statement()?),
-        ))
-        } else {
-            Ok(Statement::If (
+            StatementKind::IfElse(
                 Box::new(condition),
                 Box::new(consequence),
-            ))
-        }
+                Box::new(self.statement(false)?),
+            )
+        } else {
+            StatementKind::If(Box::new(condition), Box::new(consequence))
+        };
+        Ok(Statement::new(kind, start.to(self.previous_span())))
     }
 
     fn print_statement(&mut self) -> Result<Statement> {
+        let start = self.peek_span();
         self.advance();
         let expr = self.expression()?;
-        self.consume(&Token::SemiColon, "Expected ; after print".to_string());
-        Ok(Statement::Print(expr))
+        self.consume(&Token::SemiColon, "Expected ; after print".to_string())?;
+        Ok(Statement::new(StatementKind::Print(expr), start.to(self.previous_span())))
     }
 
-    fn expression_statement(&mut self) -> Result<Statement> {
+    fn return_statement(&mut self) -> Result<Statement> {
+        let start = self.peek_span();
+        self.advance();
+        let value = if self.expect(&Token::SemiColon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(&Token::SemiColon, "Expected ';' after return".to_string())?;
+        Ok(Statement::new(StatementKind::Return(value), start.to(self.previous_span())))
+    }
+
+    fn expression_statement(&mut self, tail: bool) -> Result<Statement> {
+        let start = self.peek_span();
         let expr = self.expression()?;
-        self.consume(&Token::SemiColon, "Expected ';' after expression.".to_string());
-        Ok(Statement::Expression(expr))
+        if tail && self.expect(&Token::RightBrace) {
+            let span = expr.span;
+            return Ok(Statement::new(StatementKind::Return(Some(expr)), span));
+        }
+        self.consume(&Token::SemiColon, "Expected ';' after expression.".to_string())?;
+        Ok(Statement::new(StatementKind::Expression(expr), start.to(self.previous_span())))
     }
 
     fn expression(&mut self) -> Result<Expression> {
@@ -152,14 +465,29 @@ impl Parser {
     fn assignment(&mut self) -> Result<Expression> {
         let expr = self.or()?;
 
-        println!("Assignment: {:?}", expr);
-
         if self.expect(&Token::Equal) {
             self.advance();
             let equals = self.assignment()?;
-            if let Expression::Variable(name) = expr {
-                return Ok(Expression::Assign(name, Box::new(equals)));
+            let span = expr.span.to(equals.span);
+            match expr.kind {
+                ExpressionKind::Identifier(variable) => {
+                    return Ok(Expression::new(ExpressionKind::Assign(variable, Box::new(equals)), span));
+                }
+                ExpressionKind::Index(target, index) => {
+                    let kind = ExpressionKind::SetIndex(target, index, Box::new(equals));
+                    return Ok(Expression::new(kind, span));
+                }
+                ExpressionKind::Field(target, field) => {
+                    let kind = ExpressionKind::SetField(target, field, Box::new(equals));
+                    return Ok(Expression::new(kind, span));
+                }
+                _ => (),
             }
+            return Err(self.error(
+                ParseErrorKind::InvalidAssignmentTarget,
+                "Invalid assignment target".to_string(),
+                expr.span,
+            ));
         }
         Ok(expr)
     }
@@ -171,7 +499,7 @@ impl Parser {
             self.advance();
             let op = self.previous();
             let right = self.and()?;
-            expr = Expression::Infix(Box::new(expr), op, Box::new(right));
+            expr = Self::infix(expr, op, right);
         }
 
         Ok(expr)
@@ -184,7 +512,7 @@ impl Parser {
             self.advance();
             let op = self.previous();
             let right = self.equality()?;
-            expr = Expression::Infix(Box::new(expr), op, Box::new(right));
+            expr = Self::infix(expr, op, right);
         }
 
         Ok(expr)
@@ -197,20 +525,34 @@ impl Parser {
             self.advance();
             let op = self.previous();
             let right = self.comparison()?;
-            expr = Expression::Infix(Box::new(expr), op, Box::new(right));
+            expr = Self::infix(expr, op, right);
         }
 
         Ok(expr)
     }
 
     fn comparison(&mut self) -> Result<Expression> {
-        let mut expr = self.term()?;
+        let mut expr = self.default()?;
 
         while self.expect(&Token::Greater) || self.expect(&Token::GreaterEqual) || self.expect(&Token::Less) || self.expect(&Token::LessEqual) {
             self.advance();
             let op = self.previous();
-            let right = self.term()?;
-            expr = Expression::Infix(Box::new(expr), op, Box::new(right));
+            let right = self.default()?;
+            expr = Self::infix(expr, op, right);
+        }
+
+        Ok(expr)
+    }
+
+    // `value ?? default`, binding tighter than comparisons so `x ?? 0 > 1`
+    // compares the result. It associates to the right
+    fn default(&mut self) -> Result<Expression> {
+        let expr = self.term()?;
+
+        if self.expect(&Token::DoubleQuestion) {
+            self.advance();
+            let right = self.default()?;
+            return Ok(Self::infix(expr, Token::DoubleQuestion, right));
         }
 
         Ok(expr)
@@ -225,12 +567,12 @@ impl Parser {
                 Token::Plus => {
                     self.advance();
                     let right = self.factor()?;
-                    expr = Expression::Infix(Box::new(expr), Token::Plus, Box::new(right));
+                    expr = Self::infix(expr, Token::Plus, right);
                 }
                 Token::Minus => {
                     self.advance();
                     let right = self.factor()?;
-                    expr = Expression::Infix(Box::new(expr), Token::Minus, Box::new(right));
+                    expr = Self::infix(expr, Token::Minus, right);
                 }
                 _ => break,
             }
@@ -247,12 +589,12 @@ impl Parser {
                 Token::Times => {
                     self.advance();
                     let right = self.prefix()?;
-                    expr = Expression::Infix(Box::new(expr), Token::Times, Box::new(right));
+                    expr = Self::infix(expr, Token::Times, right);
                 }
                 Token::Divide => {
                     self.advance();
                     let right = self.prefix()?;
-                    expr = Expression::Infix(Box::new(expr), Token::Divide, Box::new(right));
+                    expr = Self::infix(expr, Token::Divide, right);
                 }
                 _ => break,
             }
@@ -260,64 +602,339 @@ impl Parser {
         Ok(expr)
     }
 
+    fn infix(left: Expression, op: Token, right: Expression) -> Expression {
+        let span = left.span.to(right.span);
+        Expression::new(ExpressionKind::Infix(Box::new(left), op, Box::new(right)), span)
+    }
+
     fn prefix(&mut self) -> Result<Expression> {
         if self.expect(&Token::Minus) || self.expect(&Token::Bang) {
+            let start = self.peek_span();
             self.advance();
             let op = self.previous();
-            let right = self.atom()?;
-            Ok(Expression::Prefix(op, Box::new(right)))
+            let right = self.call()?;
+            let span = start.to(right.span);
+            Ok(Expression::new(ExpressionKind::Prefix(op, Box::new(right)), span))
         } else {
-            self.atom()
+            self.call()
         }
     }
 
-    fn atom(&mut self) -> Result<Expression> {
-        match self.peek() {
-            Token::Integer(_) => {
-                match self.advance() {
-                    Token::Integer(i) => Ok(Expression::Integer(i)),
-                    _ => unreachable!(),
-                }
+    // expressions separated by commas up to `end`, which is consumed. A
+    // trailing comma is allowed
+    fn list(&mut self, end: &Token, message: &str) -> Result<Vec<Expression>> {
+        let mut items = Vec::new();
+        while !self.expect(end) {
+            items.push(self.expression()?);
+            if !self.expect(&Token::Comma) {
+                break;
             }
-            Token::Float(_) => {
-                match self.advance() {
-                    Token::Float(f) => Ok(Expression::Float(f)),
-                    _ => unreachable!(),
-                }
+            self.advance();
+        }
+        self.consume(end, message.to_string())?;
+        Ok(items)
+    }
+
+    // the `key: value` pairs of a map literal up to its '}', after the '{'
+    fn map_entries(&mut self) -> Result<Vec<(Expression, Expression)>> {
+        let mut entries = Vec::new();
+        while !self.expect(&Token::RightBrace) {
+            let key = self.expression()?;
+            self.consume(&Token::Colon, "Expected ':' after map key".to_string())?;
+            entries.push((key, self.expression()?));
+            if !self.expect(&Token::Comma) {
+                break;
             }
-            Token::String(_) => {
-                match self.advance() {
-                    Token::String(s) => Ok(Expression::String(s)),
-                    _ => unreachable!(),
-                }
+            self.advance();
+        }
+        self.consume(&Token::RightBrace, "Expected '}' after map entries".to_string())?;
+        Ok(entries)
+    }
+
+    // the `field: value` pairs of a struct literal up to its '}', after the '{'
+    fn fields(&mut self) -> Result<Vec<(String, Expression)>> {
+        let mut fields = Vec::new();
+        while !self.expect(&Token::RightBrace) {
+            let name = self.expect_identifier()?;
+            self.advance();
+            self.consume(&Token::Colon, "Expected ':' after field name".to_string())?;
+            fields.push((name, self.expression()?));
+            if !self.expect(&Token::Comma) {
+                break;
             }
-            Token::Bool(_) => {
-                match self.advance() {
-                    Token::Bool(b) => Ok(Expression::Boolean(b)),
-                    _ => unreachable!(),
+            self.advance();
+        }
+        self.consume(&Token::RightBrace, "Expected '}' after struct fields".to_string())?;
+        Ok(fields)
+    }
+
+    // a '{' at the start of a statement opens a block unless it is followed
+    // by a key and ':', as in `{"a": 1}.len();`. An empty `{}` is a block
+    fn map_ahead(&self) -> bool {
+        matches!(self.tokens.get(self.pos + 2), Some(lexeme) if lexeme.token == Token::Colon)
+    }
+
+    // an atom followed by any number of argument lists, indexes, slices,
+    // fields and method calls, with `.` or `?.`
+    fn call(&mut self) -> Result<Expression> {
+        let mut expr = self.atom()?;
+
+        loop {
+            let start = expr.span;
+            let kind = match self.peek() {
+                Token::LeftParen => {
+                    self.advance();
+                    let args = self.list(&Token::RightParen, "Expected ')' after arguments")?;
+                    ExpressionKind::Call(Box::new(expr), args)
                 }
-            }
-            Token::Identifier(_) => {
-                match self.advance() {
-                    Token::Identifier(i) => Ok(Expression::Identifier(i)),
-                    _ => unreachable!(),
+                Token::LeftBracket => {
+                    self.advance();
+                    self.index(expr)?
                 }
+                Token::Dot => {
+                    self.advance();
+                    let name = self.expect_identifier()?;
+                    self.advance();
+                    if self.expect(&Token::LeftParen) {
+                        self.advance();
+                        let args = self.list(&Token::RightParen, "Expected ')' after arguments")?;
+                        ExpressionKind::Method(Box::new(expr), name, args)
+                    } else {
+                        ExpressionKind::Field(Box::new(expr), name)
+                    }
+                }
+                Token::QuestionDot => {
+                    self.advance();
+                    let name = self.expect_identifier()?;
+                    self.advance();
+                    if self.expect(&Token::LeftParen) {
+                        self.advance();
+                        let args = self.list(&Token::RightParen, "Expected ')' after arguments")?;
+                        ExpressionKind::SafeMethod(Box::new(expr), name, args)
+                    } else {
+                        ExpressionKind::SafeField(Box::new(expr), name)
+                    }
+                }
+                _ => break,
+            };
+            expr = Expression::new(kind, start.to(self.previous_span()));
+        }
+        Ok(expr)
+    }
+
+    // the rest of `target[index]` or `target[start:end]`, after the '['
+    fn index(&mut self, target: Expression) -> Result<ExpressionKind> {
+        let start = if self.expect(&Token::Colon) { None } else { Some(Box::new(self.expression()?)) };
+        let kind = match start {
+            Some(index) if !self.expect(&Token::Colon) => ExpressionKind::Index(Box::new(target), index),
+            start => {
+                self.advance();
+                let end = if self.expect(&Token::RightBracket) { None } else { Some(Box::new(self.expression()?)) };
+                ExpressionKind::Slice(Box::new(target), start, end)
+            }
+        };
+        self.consume(&Token::RightBracket, "Expected ']' after index".to_string())?;
+        Ok(kind)
+    }
+
+    fn atom(&mut self) -> Result<Expression> {
+        let span = self.peek_span();
+        let kind = match self.peek() {
+            Token::Integer(i) => ExpressionKind::Integer(i),
+            Token::Float(f) => ExpressionKind::Float(f),
+            Token::String(s) => ExpressionKind::String(s),
+            Token::Interpolated(parts) => ExpressionKind::Interpolation(self.interpolation(parts)?),
+            Token::Bool(b) => ExpressionKind::Boolean(b),
+            Token::Null => ExpressionKind::Null,
+            Token::Identifier(i) if self.peek_next() == Token::LeftBrace => {
+                self.advance();
+                self.advance();
+                let fields = self.fields()?;
+                let span = span.to(self.previous_span());
+                return Ok(Expression::new(ExpressionKind::Instance(i, fields), span));
+            }
+            Token::Identifier(i) if self.peek_next() == Token::DoubleColon => {
+                let (variant, args) = self.variant()?;
+                let span = span.to(self.previous_span());
+                return Ok(Expression::new(ExpressionKind::Variant(i, variant, args), span));
+            }
+            Token::Identifier(i) => ExpressionKind::Identifier(Variable::new(i)),
+            Token::Match => return self.match_expression(),
+            Token::SelfValue => ExpressionKind::Identifier(Variable::new("self".to_string())),
+            Token::Fn => {
+                self.advance();
+                let function = self.function("anonymous".to_string(), span, None)?;
+                let span = function.span;
+                return Ok(Expression::new(ExpressionKind::Lambda(Rc::new(function)), span));
+            }
+            Token::LeftBracket => {
+                self.advance();
+                let elements = self.list(&Token::RightBracket, "Expected ']' after array elements")?;
+                let span = span.to(self.previous_span());
+                return Ok(Expression::new(ExpressionKind::Array(elements), span));
+            }
+            Token::LeftBrace => {
+                self.advance();
+                let entries = self.map_entries()?;
+                let span = span.to(self.previous_span());
+                return Ok(Expression::new(ExpressionKind::Map(entries), span));
             }
             Token::LeftParen => {
                 self.advance();
-                let expr = self.expression()?;
-                self.consume(&Token::RightParen, "Expected ')' after expression.".to_string());
-                Ok(expr)
+                let mut expr = self.expression()?;
+                self.consume(&Token::RightParen, "Expected ')' after expression.".to_string())?;
+                expr.span = span.to(self.previous_span());
+                return Ok(expr);
+            }
+            _ => {
+                return Err(self.error(
+                    ParseErrorKind::ExpectedExpression,
+                    "Expected expression".to_string(),
+                    span,
+                ))
+            }
+        };
+        self.advance();
+        Ok(Expression::new(kind, span))
+    }
+
+    // the variant and arguments of `Enum::Variant(args)`, from the enum name
+    fn variant(&mut self) -> Result<(String, Vec<Expression>)> {
+        self.advance();
+        self.advance();
+        let variant = self.expect_identifier()?;
+        self.advance();
+        let mut args = Vec::new();
+        if self.expect(&Token::LeftParen) {
+            self.advance();
+            args = self.list(&Token::RightParen, "Expected ')' after arguments")?;
+        }
+        Ok((variant, args))
+    }
+
+    fn match_expression(&mut self) -> Result<Expression> {
+        let start = self.peek_span();
+        self.advance();
+        self.consume(&Token::LeftParen, "Expected '(' after 'match'".to_string())?;
+        let subject = self.expression()?;
+        self.consume(&Token::RightParen, "Expected ')' after match subject".to_string())?;
+        self.consume(&Token::LeftBrace, "Expected '{' before match arms".to_string())?;
+        let mut arms = Vec::new();
+        while !self.expect(&Token::RightBrace) {
+            let pattern = self.pattern()?;
+            let guard = if self.expect(&Token::If) {
+                self.advance();
+                Some(self.expression()?)
+            } else {
+                None
+            };
+            self.consume(&Token::FatArrow, "Expected '=>' after pattern".to_string())?;
+            let body = self.expression()?;
+            arms.push(Arm { pattern, guard, body });
+            if !self.expect(&Token::Comma) {
+                break;
             }
-            _ => Err(anyhow::anyhow!("Expected expression")),
+            self.advance();
         }
+        self.consume(&Token::RightBrace, "Expected '}' after match arms".to_string())?;
+        let span = start.to(self.previous_span());
+        Ok(Expression::new(ExpressionKind::Match(Box::new(subject), arms), span))
     }
 
-    fn consume(&mut self, t: &Token, message: String) -> Token {
+    // a literal, `_`, a name to bind or `Enum::Variant(patterns)`
+    fn pattern(&mut self) -> Result<Pattern> {
+        let span = self.peek_span();
+        let kind = match self.peek() {
+            Token::Integer(i) => PatternKind::Integer(i),
+            Token::Float(f) => PatternKind::Float(f),
+            Token::Minus => {
+                self.advance();
+                let kind = match self.peek() {
+                    Token::Integer(i) => PatternKind::Integer(i.wrapping_neg()),
+                    Token::Float(f) => PatternKind::Float(-f),
+                    _ => {
+                        let message = "Expected a number after '-'".to_string();
+                        return Err(self.error(ParseErrorKind::ExpectedPattern, message, self.peek_span()));
+                    }
+                };
+                self.advance();
+                return Ok(Pattern { kind, span: span.to(self.previous_span()) });
+            }
+            Token::String(s) => PatternKind::String(s),
+            Token::Bool(b) => PatternKind::Boolean(b),
+            Token::Null => PatternKind::Null,
+            Token::Identifier(i) if self.peek_next() == Token::DoubleColon => {
+                self.advance();
+                self.advance();
+                let variant = self.expect_identifier()?;
+                self.advance();
+                let mut patterns = Vec::new();
+                if self.expect(&Token::LeftParen) {
+                    self.advance();
+                    while !self.expect(&Token::RightParen) {
+                        patterns.push(self.pattern()?);
+                        if !self.expect(&Token::Comma) {
+                            break;
+                        }
+                        self.advance();
+                    }
+                    self.consume(&Token::RightParen, "Expected ')' after patterns".to_string())?;
+                }
+                let span = span.to(self.previous_span());
+                return Ok(Pattern { kind: PatternKind::Variant(i, variant, patterns), span });
+            }
+            Token::Identifier(i) if i == "_" => PatternKind::Wildcard,
+            Token::Identifier(i) => PatternKind::Binding(Variable::new(i)),
+            _ => return Err(self.error(ParseErrorKind::ExpectedPattern, "Expected pattern".to_string(), span)),
+        };
+        self.advance();
+        Ok(Pattern { kind, span })
+    }
+
+    // the expressions of an interpolated string, each parsed from its own
+    // tokens. Errors recovered from inside them, in the body of a lambda, are
+    // kept with the rest
+    fn interpolation(&mut self, parts: Vec<StringPart>) -> Result<Vec<Fragment>> {
+        let mut fragments = Vec::new();
+        for part in parts {
+            match part {
+                StringPart::Text(s) => fragments.push(Fragment::Text(s)),
+                StringPart::Code(code) => {
+                    let mut parser = Parser::new(code);
+                    let result = parser.expression();
+                    self.errors.append(&mut parser.errors);
+                    let expr = result?;
+                    parser.consume(&Token::RightBrace, "Expected '}' after expression in string".to_string())?;
+                    if !parser.done() {
+                        let message = "Expected the end of the string after '}'".to_string();
+                        return Err(parser.error(ParseErrorKind::Expected(Token::Eof), message, parser.peek_span()));
+                    }
+                    fragments.push(Fragment::Expression(expr));
+                }
+            }
+        }
+        Ok(fragments)
+    }
+
+    fn consume(&mut self, t: &Token, message: String) -> Result<Token> {
         if self.expect(t) {
-           self.advance()
+            return Ok(self.advance());
+        }
+        // a missing terminator reads best pointed at just after the last token
+        let span = if self.pos > 0 && self.peek_span().line > self.previous_span().line {
+            self.previous_span().after()
         } else {
-            panic!("{}", message);
+            self.peek_span()
+        };
+        Err(self.error(ParseErrorKind::Expected(t.clone()), message, span))
+    }
+
+    fn error(&self, kind: ParseErrorKind, message: String, span: Span) -> ParseError {
+        ParseError {
+            kind,
+            message,
+            span,
+            found: self.peek(),
         }
     }
 
@@ -326,20 +943,71 @@ impl Parser {
         if self.done() {
             return false;
         }
-        if &self.peek() == t {
-            return true;
-        }
-        false
+        &self.peek() == t
     }
 
     fn expect_identifier(&mut self) -> Result<String> {
-        if self.done() {
-            return Err(anyhow::anyhow!("Expected identifier"));
-        }
         match self.peek() {
             Token::Identifier(s) => Ok(s),
-            _ => Err(anyhow::anyhow!("Expected identifier")),
+            _ => Err(self.error(
+                ParseErrorKind::ExpectedIdentifier,
+                "Expected identifier".to_string(),
+                self.peek_span(),
+            )),
+        }
+    }
+
+    // a type annotation, `T?` allows null as well as a T. After a function
+    // type the `?` belongs to its return type
+    fn type_name(&mut self) -> Result<TypeAnnotation> {
+        let ty = self.base_type()?;
+        if self.expect(&Token::Question) {
+            self.advance();
+            return Ok(TypeAnnotation::Optional(Box::new(ty)));
+        }
+        Ok(ty)
+    }
+
+    // `null` is a keyword so it is accepted explicitly
+    fn base_type(&mut self) -> Result<TypeAnnotation> {
+        if self.expect(&Token::Null) {
+            self.advance();
+            return Ok(TypeAnnotation::Named("null".to_string()));
+        }
+        if self.expect(&Token::Fn) {
+            self.advance();
+            self.consume(&Token::LeftParen, "Expected '(' after 'fn'".to_string())?;
+            let mut params = Vec::new();
+            if !self.expect(&Token::RightParen) {
+                loop {
+                    params.push(self.type_name()?);
+                    if !self.expect(&Token::Comma) {
+                        break;
+                    }
+                    self.advance();
+                }
+            }
+            self.consume(&Token::RightParen, "Expected ')' after parameter types".to_string())?;
+            self.consume(&Token::Arrow, "Expected '->' after parameter types".to_string())?;
+            let ret = self.type_name()?;
+            return Ok(TypeAnnotation::Function(params, Box::new(ret)));
         }
+        let ty = self.expect_identifier()?;
+        self.advance();
+        if !self.expect(&Token::Less) {
+            return Ok(TypeAnnotation::Named(ty));
+        }
+        self.advance();
+        let mut args = Vec::new();
+        loop {
+            args.push(self.type_name()?);
+            if !self.expect(&Token::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        self.consume(&Token::Greater, "Expected '>' after type arguments".to_string())?;
+        Ok(TypeAnnotation::Generic(ty, args))
     }
 
     // advances the parser by 1 token
@@ -357,11 +1025,29 @@ impl Parser {
 
     // get the next token early
    fn peek(&self) -> Token {
-        self.tokens[self.pos].clone()
+        self.tokens[self.pos].token.clone()
+    }
+
+    // the token after the next one
+    fn peek_next(&self) -> Token {
+        match self.tokens.get(self.pos + 1) {
+            Some(lexeme) => lexeme.token.clone(),
+            None => Token::Eof,
+        }
     }
 
    // last token
    fn previous(&self) -> Token {
-        self.tokens[self.pos - 1].clone()
+        self.tokens[self.pos - 1].token.clone()
    }
+
+    // where the next token is
+    fn peek_span(&self) -> Span {
+        self.tokens[self.pos].span
+    }
+
+    // where the last token was
+    fn previous_span(&self) -> Span {
+        self.tokens[self.pos - 1].span
+    }
 }
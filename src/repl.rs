@@ -0,0 +1,319 @@
+use std::fs;
+use std::path::PathBuf;
+
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+use crate::ast::{self, Statement, StatementKind};
+use crate::diagnostics::{self, Diagnostic};
+use crate::eval::Evaluator;
+use crate::lexer::{Lexer, KEYWORDS};
+use crate::parser::Parser;
+use crate::resolver::Resolver;
+use crate::tokens::Token;
+use crate::typechecker::TypeChecker;
+
+const PROMPT: &str = "mt -> ";
+// shown while a statement spans several lines
+const CONTINUATION: &str = "   .. ";
+// in the user's home directory
+const HISTORY_FILE: &str = ".mt_history";
+
+const COMMANDS: &str = "\
+:type <expr>    show the type of an expression
+:ast <code>     show the syntax tree of some code
+:tokens         turn printing the tokens of each input on or off
+:env            list the variables bound in this session
+:load <file>    run a file in this session
+:history        list everything entered so far
+:reset          forget every variable
+:help           show this list";
+
+// what the REPL made of the input so far
+enum Parsed {
+    Complete(Vec<Statement>),
+    // ran out of input mid-statement, the errors are reported if the user
+    // gives up by entering a blank line
+    Incomplete(Vec<Diagnostic>),
+    Invalid(Vec<Diagnostic>),
+}
+
+// everything the REPL remembers from one input to the next
+pub struct Session {
+    resolver: Resolver,
+    checker: TypeChecker,
+    evaluator: Evaluator,
+    debug: bool,
+    history: Vec<String>,
+}
+
+impl Session {
+    pub fn new(debug: bool) -> Session {
+        Session {
+            resolver: Resolver::for_repl(),
+            checker: TypeChecker::new(),
+            evaluator: Evaluator::new(),
+            debug,
+            history: Vec::new(),
+        }
+    }
+
+    fn parse(&self, source: &str) -> Parsed {
+        let mut l = Lexer::new(source.to_string());
+        let tokens = l.lex(self.debug);
+        if !l.errors().is_empty() {
+            // a block comment, raw or `"""` string still open at the end can
+            // go on over the next lines, an ordinary string cannot
+            let open = |d: &Diagnostic| d.code == "E0002" || (d.code == "E0003" && d.span.end - d.span.start > 1);
+            if l.errors().iter().any(open) {
+                return Parsed::Incomplete(l.errors().to_vec());
+            }
+            return Parsed::Invalid(l.errors().to_vec());
+        }
+
+        match Parser::new(tokens).parse() {
+            Ok(ast) => Parsed::Complete(ast),
+            Err(errors) => {
+                let incomplete = errors[0].found == Token::Eof;
+                let errors = errors.iter().map(|e| e.to_diagnostic()).collect();
+                if incomplete {
+                    Parsed::Incomplete(errors)
+                } else {
+                    Parsed::Invalid(errors)
+                }
+            }
+        }
+    }
+
+    // parse `source`, adding the `;` an expression typed at a command is
+    // usually missing
+    fn parse_snippet(&self, source: &str) -> Result<Vec<Statement>, Vec<Diagnostic>> {
+        match self.parse(source) {
+            Parsed::Complete(ast) => Ok(ast),
+            Parsed::Incomplete(errors) => match self.parse(&format!("{};", source)) {
+                Parsed::Complete(ast) => Ok(ast),
+                _ => Err(errors),
+            },
+            Parsed::Invalid(errors) => Err(errors),
+        }
+    }
+
+    // resolve, check and run one complete input. If any stage fails the
+    // names it declared are forgotten, values already assigned are kept
+    fn execute(&mut self, ast: Vec<Statement>, fname: &str, source: &str) {
+        let resolver = self.resolver.clone();
+        let checker = self.checker.clone();
+
+        let errors: Vec<Diagnostic> = if let Err(errors) = self.resolver.resolve(&ast) {
+            errors.iter().map(|e| e.to_diagnostic()).collect()
+        } else if let Err(errors) = self.checker.check(&ast) {
+            errors.iter().map(|e| e.to_diagnostic()).collect()
+        } else if let Err(error) = self.evaluator.eval(ast) {
+            vec![error.to_diagnostic()]
+        } else {
+            return;
+        };
+
+        diagnostics::report(&errors, fname, source);
+        self.resolver = resolver;
+        self.checker = checker;
+    }
+
+    // the type of an expression, checked against the session without
+    // changing it
+    fn show_type(&self, source: &str) {
+        let ast = match self.parse_snippet(source) {
+            Ok(ast) => ast,
+            Err(errors) => return diagnostics::report(&errors, "<repl>", source),
+        };
+        if ast.len() != 1 || !matches!(ast[0].kind, StatementKind::Expression(_)) {
+            println!(":type expects a single expression");
+            return;
+        }
+
+        if let Err(errors) = self.resolver.clone().resolve(&ast) {
+            let errors: Vec<_> = errors.iter().map(|e| e.to_diagnostic()).collect();
+            return diagnostics::report(&errors, "<repl>", source);
+        }
+        match self.checker.clone().check(&ast) {
+            Ok(types) => println!("{}", types[0]),
+            Err(errors) => {
+                let errors: Vec<_> = errors.iter().map(|e| e.to_diagnostic()).collect();
+                diagnostics::report(&errors, "<repl>", source);
+            }
+        }
+    }
+
+    fn show_env(&self) {
+        for (name, index) in self.resolver.globals() {
+            let ty = self.checker.global(&name).map(|t| t.to_string()).unwrap_or_default();
+            match self.evaluator.global(index) {
+                Some(value) => println!("{}: {} = {}", name, ty, value),
+                None => println!("{}: {}", name, ty),
+            }
+        }
+    }
+
+    fn load(&mut self, fname: &str) {
+        let source = match fs::read_to_string(fname) {
+            Ok(source) => source,
+            Err(err) => return println!("Cannot read {}: {}", fname, err),
+        };
+        match self.parse(&source) {
+            Parsed::Complete(ast) => self.execute(ast, fname, &source),
+            Parsed::Incomplete(errors) | Parsed::Invalid(errors) => {
+                diagnostics::report(&errors, fname, &source);
+            }
+        }
+    }
+
+    // run a `:` command
+    fn command(&mut self, line: &str) {
+        let (name, arg) = match line.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (line, ""),
+        };
+        match name {
+            ":type" => self.show_type(arg),
+            ":ast" => match self.parse_snippet(arg) {
+                Ok(ast) => print!("{}", ast::outline(&ast)),
+                Err(errors) => diagnostics::report(&errors, "<repl>", arg),
+            },
+            ":tokens" => {
+                self.debug = !self.debug;
+                println!("Printing tokens {}", if self.debug { "on" } else { "off" });
+            }
+            ":env" => self.show_env(),
+            ":load" => self.load(arg),
+            ":history" => {
+                for (i, input) in self.history.iter().enumerate() {
+                    println!("{:>4}  {}", i + 1, input.trim_end());
+                }
+            }
+            ":reset" => {
+                let history = std::mem::take(&mut self.history);
+                *self = Session {
+                    history,
+                    ..Session::new(self.debug)
+                };
+            }
+            ":help" => println!("{}", COMMANDS),
+            _ => println!("Unknown command '{}', the commands are:\n{}", name, COMMANDS),
+        }
+    }
+}
+
+// completes keywords, `:` commands and the names bound in the session
+struct Completions {
+    names: Vec<String>,
+}
+
+impl Completer for Completions {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':'))
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let word = &line[start..pos];
+
+        let candidates: Vec<String> = if word.starts_with(':') {
+            COMMANDS.lines().filter_map(|l| l.split_whitespace().next()).map(String::from).collect()
+        } else {
+            let keywords = KEYWORDS.iter().map(|(keyword, _)| keyword.to_string());
+            keywords.chain(self.names.iter().cloned()).collect()
+        };
+        let mut matches: Vec<String> = candidates.into_iter().filter(|c| c.starts_with(word)).collect();
+        matches.sort();
+        matches.dedup();
+        Ok((start, matches))
+    }
+}
+
+impl Hinter for Completions {
+    type Hint = String;
+}
+
+impl Highlighter for Completions {}
+
+impl Validator for Completions {}
+
+impl Helper for Completions {}
+
+// where inputs are kept between sessions, None without a home directory
+fn history_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+pub fn repl(debug: bool) {
+    let mut editor: Editor<Completions, DefaultHistory> = match Editor::new() {
+        Ok(editor) => editor,
+        Err(err) => return eprintln!("Cannot start the REPL: {}", err),
+    };
+    editor.set_helper(Some(Completions { names: Vec::new() }));
+    let history = history_file();
+    if let Some(path) = &history {
+        // there is no history file before the first session
+        let _ = editor.load_history(path);
+    }
+
+    let mut session = Session::new(debug);
+    let mut input = String::new();
+    loop {
+        if let Some(helper) = editor.helper_mut() {
+            helper.names = session.resolver.globals().into_iter().map(|(name, _)| name).collect();
+        }
+        let prompt = if input.is_empty() { PROMPT } else { CONTINUATION };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // Ctrl-C drops a half-typed statement, Ctrl-D leaves
+            Err(ReadlineError::Interrupted) => {
+                input.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("Cannot read input: {}", err);
+                break;
+            }
+        };
+
+        if input.is_empty() && line.trim_start().starts_with(':') {
+            let command = line.trim();
+            let _ = editor.add_history_entry(command);
+            session.command(command);
+            session.history.push(command.to_string());
+            continue;
+        }
+
+        let blank = line.trim().is_empty();
+        input.push_str(&line);
+        input.push('\n');
+        if input.trim().is_empty() {
+            input.clear();
+            continue;
+        }
+
+        match session.parse(&input) {
+            Parsed::Complete(ast) => session.execute(ast, "<repl>", &input),
+            Parsed::Incomplete(_) if !blank => continue,
+            Parsed::Incomplete(errors) | Parsed::Invalid(errors) => {
+                diagnostics::report(&errors, "<repl>", &input);
+            }
+        }
+        let _ = editor.add_history_entry(input.trim_end());
+        session.history.push(std::mem::take(&mut input));
+    }
+
+    if let Some(path) = &history {
+        if let Err(err) = editor.save_history(path) {
+            eprintln!("Cannot save history to {}: {}", path.display(), err);
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::*;
+use crate::diagnostics::Diagnostic;
+use crate::tokens::Span;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ResolveErrorKind {
+    UndefinedVariable,
+    UsedBeforeDeclaration,
+    Redeclaration,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ResolveError {
+    pub kind: ResolveErrorKind,
+    pub name: String,
+    pub span: Span,
+}
+
+impl ResolveError {
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        match self.kind {
+            ResolveErrorKind::UndefinedVariable => Diagnostic::error(
+                "E0400",
+                format!("Undefined variable '{}'", self.name),
+                self.span,
+            )
+            .with_label("not found in this scope".to_string()),
+            ResolveErrorKind::UsedBeforeDeclaration => Diagnostic::error(
+                "E0401",
+                format!("Variable '{}' is used before it is declared", self.name),
+                self.span,
+            )
+            .with_help("move the declaration above this line".to_string()),
+            ResolveErrorKind::Redeclaration => Diagnostic::error(
+                "E0402",
+                format!("'{}' is already declared in this scope", self.name),
+                self.span,
+            )
+            .with_help("use a different name, or declare it in an inner block to shadow it".to_string()),
+        }
+    }
+}
+
+// one runtime scope: the global scope, a block or a function call frame
+#[derive(Default, Clone)]
+struct Scope {
+    slots: HashMap<String, usize>,
+    // slots handed out, more than `slots.len()` once a name is redeclared
+    size: usize,
+    // declared somewhere in this scope but not reached yet
+    pending: HashSet<String>,
+}
+
+// works out the slot of every variable use so the evaluator never looks a name
+// up at runtime. Scopes here must line up exactly with the environments the
+// evaluator creates
+#[derive(Clone)]
+pub struct Resolver {
+    scopes: Vec<Scope>,
+    errors: Vec<ResolveError>,
+    // let a global declaration replace an earlier one with the same name
+    redeclare_globals: bool,
+}
+
+impl Resolver {
+    pub fn new() -> Resolver {
+        Resolver {
+            scopes: vec![Scope::default()],
+            errors: Vec::new(),
+            redeclare_globals: false,
+        }
+    }
+
+    // a resolver for the REPL, where redefining a global is normal. The new
+    // declaration gets a fresh slot so closures made earlier keep the old one
+    pub fn for_repl() -> Resolver {
+        Resolver {
+            redeclare_globals: true,
+            ..Resolver::new()
+        }
+    }
+
+    fn error(&mut self, kind: ResolveErrorKind, name: &str, span: Span) {
+        self.errors.push(ResolveError {
+            kind,
+            name: name.to_string(),
+            span,
+        });
+    }
+
+    // note the names a statement list declares so an early use can be told
+    // apart from a missing one. Top level functions are declared here, before
+    // anything else, so they can call each other whatever their order
+    fn collect(&mut self, stmts: &[Statement]) {
+        let global = self.scopes.len() == 1;
+        for stmt in stmts {
+            match &stmt.kind {
+                StatementKind::Function(v, _) if global => v.slot.set(Some(self.declare(&v.name, stmt.span))),
+                StatementKind::Let(v, _, _) | StatementKind::Function(v, _) => {
+                    self.scopes.last_mut().unwrap().pending.insert(v.name.clone());
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &str, span: Span) -> Slot {
+        let global = self.scopes.len() == 1;
+        let scope = self.scopes.last_mut().unwrap();
+        scope.pending.remove(name);
+        if let Some(&index) = scope.slots.get(name) {
+            if !(global && self.redeclare_globals) {
+                self.error(ResolveErrorKind::Redeclaration, name, span);
+                return Slot { depth: 0, index };
+            }
+        }
+        let index = scope.size;
+        scope.size += 1;
+        scope.slots.insert(name.to_string(), index);
+        Slot { depth: 0, index }
+    }
+
+    fn lookup(&mut self, variable: &Variable, span: Span) {
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some(&index) = scope.slots.get(&variable.name) {
+                variable.slot.set(Some(Slot { depth, index }));
+                return;
+            }
+        }
+        let kind = if self.scopes.iter().any(|s| s.pending.contains(&variable.name)) {
+            ResolveErrorKind::UsedBeforeDeclaration
+        } else {
+            ResolveErrorKind::UndefinedVariable
+        };
+        self.error(kind, &variable.name, span);
+    }
+
+    fn scoped(&mut self, stmts: &[Statement]) {
+        self.scopes.push(Scope::default());
+        self.statements(stmts);
+        self.scopes.pop();
+    }
+
+    fn statements(&mut self, stmts: &[Statement]) {
+        self.collect(stmts);
+        for stmt in stmts {
+            self.statement(stmt);
+        }
+    }
+
+    // parameters take the first slots of the call frame, in order
+    fn function(&mut self, f: &Function) {
+        self.scopes.push(Scope::default());
+        for param in &f.params {
+            self.declare(&param.name, param.span);
+        }
+        self.statements(&f.body);
+        self.scopes.pop();
+    }
+
+    fn statement(&mut self, stmt: &Statement) {
+        match &stmt.kind {
+            StatementKind::Expression(e) | StatementKind::Print(e) => self.expression(e),
+            StatementKind::If(e, s) => {
+                self.expression(e);
+                self.statement(s);
+            }
+            StatementKind::While(e, s) => {
+                self.expression(e);
+                self.statement(s);
+            }
+            StatementKind::IfElse(e, s1, s2) => {
+                self.expression(e);
+                self.statement(s1);
+                self.statement(s2);
+            }
+            StatementKind::Let(v, _, e) => {
+                // resolved before the name exists, so `let x: int64 = x;`
+                // refers to an outer `x`
+                self.expression(e);
+                v.slot.set(Some(self.declare(&v.name, stmt.span)));
+            }
+            StatementKind::Block(stmts) => self.scoped(stmts),
+            StatementKind::Function(v, f) => {
+                // declared first so the function can call itself, unless it
+                // is at the top level and `collect` has already
+                if v.slot.get().is_none() {
+                    v.slot.set(Some(self.declare(&v.name, stmt.span)));
+                }
+                self.function(f);
+            }
+            StatementKind::Return(value) => {
+                if let Some(e) = value {
+                    self.expression(e);
+                }
+            }
+            // a struct or enum only names a type, and methods are found
+            // through the value they are called on
+            StatementKind::Struct(_) | StatementKind::Enum(_) => (),
+            StatementKind::Impl(_, methods) => {
+                for method in methods {
+                    self.function(method);
+                }
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression) {
+        match &expr.kind {
+            ExpressionKind::Identifier(v) => self.lookup(v, expr.span),
+            ExpressionKind::Assign(v, e) => {
+                self.expression(e);
+                self.lookup(v, expr.span);
+            }
+            ExpressionKind::Prefix(_, e) => self.expression(e),
+            ExpressionKind::Infix(l, _, r) => {
+                self.expression(l);
+                self.expression(r);
+            }
+            ExpressionKind::Call(callee, args) | ExpressionKind::Method(callee, _, args) |
+                ExpressionKind::SafeMethod(callee, _, args) => {
+                self.expression(callee);
+                for arg in args {
+                    self.expression(arg);
+                }
+            }
+            ExpressionKind::Array(elements) => {
+                for element in elements {
+                    self.expression(element);
+                }
+            }
+            ExpressionKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.expression(value);
+                }
+            }
+            ExpressionKind::Index(target, index) => {
+                self.expression(target);
+                self.expression(index);
+            }
+            ExpressionKind::Slice(target, start, end) => {
+                self.expression(target);
+                for bound in [start, end].into_iter().flatten() {
+                    self.expression(bound);
+                }
+            }
+            ExpressionKind::SetIndex(target, index, value) => {
+                self.expression(target);
+                self.expression(index);
+                self.expression(value);
+            }
+            ExpressionKind::Instance(_, fields) => {
+                for (_, value) in fields {
+                    self.expression(value);
+                }
+            }
+            ExpressionKind::Variant(_, _, args) => {
+                for arg in args {
+                    self.expression(arg);
+                }
+            }
+            // each arm is a scope holding the names its pattern binds, in
+            // order, whether or not it binds any
+            ExpressionKind::Match(subject, arms) => {
+                self.expression(subject);
+                for arm in arms {
+                    self.scopes.push(Scope::default());
+                    for (v, span) in arm.pattern.bindings() {
+                        v.slot.set(Some(self.declare(&v.name, span)));
+                    }
+                    if let Some(guard) = &arm.guard {
+                        self.expression(guard);
+                    }
+                    self.expression(&arm.body);
+                    self.scopes.pop();
+                }
+            }
+            ExpressionKind::Field(target, _) | ExpressionKind::SafeField(target, _) => self.expression(target),
+            ExpressionKind::SetField(target, _, value) => {
+                self.expression(target);
+                self.expression(value);
+            }
+            ExpressionKind::Interpolation(fragments) => {
+                for fragment in fragments {
+                    if let Fragment::Expression(e) = fragment {
+                        self.expression(e);
+                    }
+                }
+            }
+            ExpressionKind::Lambda(f) => self.function(f),
+            ExpressionKind::Integer(_) | ExpressionKind::Float(_) | ExpressionKind::String(_) |
+                ExpressionKind::Boolean(_) | ExpressionKind::Null => (),
+        }
+    }
+
+    // every global name and its slot, in declaration order
+    pub fn globals(&self) -> Vec<(String, usize)> {
+        let mut globals: Vec<_> = self.scopes[0].slots.iter().map(|(n, &i)| (n.clone(), i)).collect();
+        globals.sort_by_key(|&(_, index)| index);
+        globals
+    }
+
+    // resolve `ast` in the global scope, after anything resolved before
+    pub fn resolve(&mut self, ast: &[Statement]) -> Result<(), Vec<ResolveError>> {
+        self.statements(ast);
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+}
+
+// resolve every variable in `ast`, returning every scoping error found
+pub fn resolve(ast: &[Statement]) -> Result<(), Vec<ResolveError>> {
+    Resolver::new().resolve(ast)
+}
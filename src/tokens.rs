@@ -1,3 +1,4 @@
+use std::fmt::{Display, Formatter, Result};
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
@@ -25,7 +26,15 @@ pub enum Token {
     Comma,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Colon,
+    DoubleColon,
+    Arrow,
+    FatArrow,
+    Question,
+    DoubleQuestion,
+    QuestionDot,
 
     // invisible tokens
     WhiteSpace,
@@ -37,6 +46,9 @@ pub enum Token {
     Float(f64),
     Bool(bool),
     String(String),
+    // a string with expressions in braces, `"x = {x}"`
+    Interpolated(Vec<StringPart>),
+    Null,
 
     // Identifier 
     Identifier(String),
@@ -47,7 +59,167 @@ pub enum Token {
     Else,
     Let,
     While,
+    Fn,
+    Return,
+    Struct,
+    Impl,
+    SelfValue,
+    Enum,
+    Match,
+
+    // comments, kept as trivia on the token after them. A line or block
+    // comment holds its source text, a `///` doc comment the text after the
+    // slashes
+    Comment(String),
+    DocComment(String),
 
     // illegal
-    Illegal(char, usize, usize),
+    Illegal(char),
+}
+
+// a piece of an interpolated string: text with its escapes replaced, or the
+// tokens of an embedded expression, ending with its '}' and `Eof`
+#[derive(Debug, PartialEq, Clone)]
+pub enum StringPart {
+    Text(String),
+    Code(Vec<Lexeme>),
+}
+
+// a region of the source: byte offsets [start, end) plus the 1-based line and
+// column of `start`
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, column: usize) -> Span {
+        Span { start, end, line, column }
+    }
+
+    // an empty span just past the end of this one
+    pub fn after(self) -> Span {
+        Span {
+            start: self.end,
+            end: self.end,
+            line: self.line,
+            column: self.column + (self.end - self.start),
+        }
+    }
+
+    // the smallest span covering both `self` and `other`
+    pub fn to(self, other: Span) -> Span {
+        if other.start < self.start {
+            return other.to(self);
+        }
+        Span {
+            start: self.start,
+            end: self.end.max(other.end),
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
+
+// a token together with where it came from
+#[derive(Debug, PartialEq, Clone)]
+pub struct Lexeme {
+    pub token: Token,
+    pub span: Span,
+    // the comments between the previous token and this one
+    pub trivia: Vec<Lexeme>,
+}
+
+impl Lexeme {
+    pub fn new(token: Token, span: Span) -> Lexeme {
+        Lexeme {
+            token,
+            span,
+            trivia: Vec::new(),
+        }
+    }
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let s = match self {
+            Token::LeftParen => "(",
+            Token::RightParen => ")",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Times => "*",
+            Token::Divide => "/",
+            Token::Dot => ".",
+            Token::SemiColon => ";",
+            Token::Quote => "'",
+            Token::Or => "||",
+            Token::And => "&&",
+            Token::Xor => "^",
+            Token::Bang => "!",
+            Token::Equal => "=",
+            Token::DoubleEqual => "==",
+            Token::BangEqual => "!=",
+            Token::Greater => ">",
+            Token::Less => "<",
+            Token::GreaterEqual => ">=",
+            Token::LessEqual => "<=",
+            Token::Comma => ",",
+            Token::LeftBrace => "{",
+            Token::RightBrace => "}",
+            Token::LeftBracket => "[",
+            Token::RightBracket => "]",
+            Token::Colon => ":",
+            Token::DoubleColon => "::",
+            Token::Arrow => "->",
+            Token::FatArrow => "=>",
+            Token::Question => "?",
+            Token::DoubleQuestion => "??",
+            Token::QuestionDot => "?.",
+            Token::WhiteSpace => " ",
+            Token::NewLine => "\\n",
+            Token::Eof => "end of file",
+            Token::Integer(i) => return write!(f, "{}", i),
+            Token::Float(fl) => return write!(f, "{:?}", fl),
+            Token::Bool(b) => return write!(f, "{}", b),
+            Token::String(s) => return write!(f, "{:?}", s),
+            Token::Interpolated(parts) => {
+                write!(f, "\"")?;
+                for part in parts {
+                    match part {
+                        StringPart::Text(s) => {
+                            let s = format!("{:?}", s).replace('{', "\\{").replace('}', "\\}");
+                            write!(f, "{}", &s[1..s.len() - 1])?;
+                        }
+                        StringPart::Code(code) => {
+                            let code: Vec<String> = code[..code.len() - 2].iter().map(|l| l.token.to_string()).collect();
+                            write!(f, "{{{}}}", code.join(" "))?;
+                        }
+                    }
+                }
+                return write!(f, "\"");
+            }
+            Token::Null => "null",
+            Token::Identifier(s) => s,
+            Token::Print => "print",
+            Token::If => "if",
+            Token::Else => "else",
+            Token::Let => "let",
+            Token::While => "while",
+            Token::Fn => "fn",
+            Token::Return => "return",
+            Token::Struct => "struct",
+            Token::Impl => "impl",
+            Token::SelfValue => "self",
+            Token::Enum => "enum",
+            Token::Match => "match",
+            Token::Comment(text) => text,
+            Token::DocComment(text) if text.is_empty() => "///",
+            Token::DocComment(text) => return write!(f, "/// {}", text),
+            Token::Illegal(c) => return write!(f, "{}", c),
+        };
+        write!(f, "{}", s)
+    }
 }
@@ -1,137 +1,1485 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::rc::Rc;
 
 use crate::ast::*;
+use crate::diagnostics::Diagnostic;
 use crate::tokens::*;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Type {
     Integer,
     Float,
     String,
     Boolean,
     Null,
+    Array(Box<Type>),
+    // key and value types
+    Map(Box<Type>, Box<Type>),
+    // a struct by name
+    Struct(String),
+    // an enum by name
+    Enum(String),
+    // parameter types and return type
+    Function(Vec<Type>, Box<Type>),
+    // a value of the type or null
+    Optional(Box<Type>),
+    // the element type of an empty array literal, which fits any array
+    Unknown,
+    // the type of anything that already failed to check, it is compatible with
+    // everything so one mistake is only reported once
+    Error,
 }
 
-fn string_to_type(s: &str) -> Type {
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Type::Integer => write!(f, "int64"),
+            Type::Float => write!(f, "float64"),
+            Type::String => write!(f, "string"),
+            Type::Boolean => write!(f, "bool"),
+            Type::Null => write!(f, "null"),
+            Type::Array(t) => write!(f, "array<{}>", t),
+            Type::Map(k, v) => write!(f, "map<{}, {}>", k, v),
+            Type::Struct(name) | Type::Enum(name) => write!(f, "{}", name),
+            Type::Unknown => write!(f, "_"),
+            Type::Function(params, ret) => {
+                let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+                write!(f, "fn({}) -> {}", params.join(", "), ret)
+            }
+            Type::Optional(t) => write!(f, "{}?", t),
+            Type::Error => write!(f, "{{error}}"),
+        }
+    }
+}
+
+fn string_to_type(s: &str) -> Option<Type> {
     match s {
-        "int64" => Type::Integer,
-        "float64" => Type::Float,
-        "string" => Type::String,
-        "bool" => Type::Boolean,
-        "null" => Type::Null,
-        _ => panic!("Invalid type"),
+        "int64" => Some(Type::Integer),
+        "float64" => Some(Type::Float),
+        "string" => Some(Type::String),
+        "bool" => Some(Type::Boolean),
+        "null" => Some(Type::Null),
+        _ => None,
+    }
+}
+
+// `ty` or null
+fn optional(ty: Type) -> Type {
+    match ty {
+        Type::Null | Type::Optional(_) | Type::Error => ty,
+        t => Type::Optional(Box::new(t)),
+    }
+}
+
+// a type that holds the values of both, e.g. `array<int64>` for `array<_>`
+// and `array<int64>`, or `int64?` for `int64` and `null`
+fn merge(a: &Type, b: &Type) -> Option<Type> {
+    match (a, b) {
+        (Type::Error | Type::Unknown, t) | (t, Type::Error | Type::Unknown) => Some(t.clone()),
+        (Type::Array(_), Type::Array(_)) | (Type::Map(..), Type::Map(..)) => unify(a, b),
+        _ if a == b => Some(a.clone()),
+        (Type::Null, t) | (t, Type::Null) => Some(optional(t.clone())),
+        (Type::Optional(x), t) | (t, Type::Optional(x)) => merge(x, t).map(optional),
+        _ => None,
+    }
+}
+
+// the type both are, with any `Unknown` or `Error` part of one filled in
+// from the other. Arrays and maps are shared, so their element types have to
+// match exactly: an `array<int64>` used as an `array<int64?>` could be given
+// a null that its other users would not expect
+fn unify(a: &Type, b: &Type) -> Option<Type> {
+    match (a, b) {
+        (Type::Error | Type::Unknown, t) | (t, Type::Error | Type::Unknown) => Some(t.clone()),
+        (Type::Array(x), Type::Array(y)) => unify(x, y).map(|t| Type::Array(Box::new(t))),
+        (Type::Map(k1, v1), Type::Map(k2, v2)) => {
+            Some(Type::Map(Box::new(unify(k1, k2)?), Box::new(unify(v1, v2)?)))
+        }
+        (Type::Optional(x), Type::Optional(y)) => unify(x, y).map(optional),
+        _ if a == b => Some(a.clone()),
+        _ => None,
+    }
+}
+
+// can a value of type `found` be used where `expected` is required. A `T?`
+// takes a T or null, but a T takes neither a `T?` nor null
+fn fits(expected: &Type, found: &Type) -> bool {
+    match (expected, found) {
+        (Type::Error | Type::Unknown, _) | (_, Type::Error | Type::Unknown) => true,
+        (Type::Optional(_), Type::Null) => true,
+        (Type::Optional(e), Type::Optional(f)) => fits(e, f),
+        (Type::Optional(e), f) => fits(e, f),
+        _ => unify(expected, found).is_some(),
+    }
+}
+
+// a type that may hold null
+fn nullable(ty: &Type) -> bool {
+    matches!(ty, Type::Null | Type::Optional(_))
+}
+
+// the parameter and return types of a built in method
+fn builtin_method(ty: &Type, name: &str) -> Option<(Vec<Type>, Type)> {
+    match (ty, name) {
+        (Type::Array(_), "len") => Some((vec![], Type::Integer)),
+        (Type::Array(t), "push") => Some((vec![(**t).clone()], Type::Null)),
+        (Type::Array(t), "pop") => Some((vec![], (**t).clone())),
+        (Type::Map(..), "len") => Some((vec![], Type::Integer)),
+        (Type::Map(k, _), "contains") => Some((vec![(**k).clone()], Type::Boolean)),
+        (Type::Map(k, v), "insert") => Some((vec![(**k).clone(), (**v).clone()], Type::Null)),
+        (Type::Map(k, v), "remove") => Some((vec![(**k).clone()], (**v).clone())),
+        (Type::Map(k, _), "keys") => Some((vec![], Type::Array(k.clone()))),
+        (Type::Map(_, v), "values") => Some((vec![], Type::Array(v.clone()))),
+        _ => None,
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum TypeErrorKind {
+    Mismatch,
+    NonBooleanCondition,
+    InvalidOperands(Token),
+    UndefinedVariable(String),
+    UnknownType(String),
+    ReturnOutsideFunction,
+    MissingReturn(String),
+    // expected and given argument counts
+    WrongArgumentCount(usize, usize),
+    NotCallable,
+    NotIndexable,
+    UnknownMethod(String),
+    InvalidKey,
+    Redeclared(String),
+    // a field or method given twice
+    DuplicateMember(String),
+    UnknownField(String),
+    MissingField(String),
+    UnknownVariant(String),
+    // the variant, its field count and the pattern's
+    WrongPatternCount(String, usize, usize),
+    // a value no arm matches, written as a pattern
+    NonExhaustive(String),
+    UnreachableArm,
+    // a `T?` used where only a T will do
+    NullableValue,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TypeError {
+    pub kind: TypeErrorKind,
+    pub expected: Option<Type>,
+    pub found: Type,
+    pub span: Span,
+}
+
+impl TypeError {
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let expected = match &self.expected {
+            Some(t) => t.to_string(),
+            None => String::new(),
+        };
+        match &self.kind {
+            TypeErrorKind::Mismatch => Diagnostic::error("E0200", "Type mismatch".to_string(), self.span)
+                .with_label(format!("expected {}, found {}", expected, self.found)),
+            TypeErrorKind::NonBooleanCondition => {
+                Diagnostic::error("E0201", "Condition must be boolean".to_string(), self.span)
+                    .with_label(format!("expected bool, found {}", self.found))
+            }
+            TypeErrorKind::InvalidOperands(op) => Diagnostic::error(
+                "E0202",
+                format!("Invalid operand types for '{}'", op),
+                self.span,
+            )
+            .with_label(format!("found {}", self.found)),
+            TypeErrorKind::UndefinedVariable(name) => Diagnostic::error(
+                "E0203",
+                format!("Undefined variable '{}'", name),
+                self.span,
+            )
+            .with_label("not found in this scope".to_string()),
+            TypeErrorKind::UnknownType(name) => {
+                let help = "the built in types are int64, float64, string, bool, null, array<T> and \
+                            map<K, V>, any other type is a struct or enum declared before it is used. \
+                            T? is a T or null";
+                Diagnostic::error("E0204", format!("Unknown type '{}'", name), self.span).with_help(help.to_string())
+            }
+            TypeErrorKind::ReturnOutsideFunction => {
+                Diagnostic::error("E0205", "'return' outside of a function".to_string(), self.span)
+            }
+            TypeErrorKind::MissingReturn(name) => Diagnostic::error(
+                "E0206",
+                format!("Function '{}' may finish without returning a value", name),
+                self.span,
+            )
+            .with_label(format!("expected to return {}", expected)),
+            TypeErrorKind::WrongArgumentCount(want, got) => Diagnostic::error(
+                "E0207",
+                format!("Expected {} arguments but got {}", want, got),
+                self.span,
+            ),
+            TypeErrorKind::NotCallable => Diagnostic::error(
+                "E0208",
+                format!("Cannot call a value of type {}", self.found),
+                self.span,
+            ),
+            TypeErrorKind::NotIndexable => Diagnostic::error(
+                "E0209",
+                format!("Cannot index a value of type {}", self.found),
+                self.span,
+            ),
+            TypeErrorKind::UnknownMethod(name) => Diagnostic::error(
+                "E0210",
+                format!("No method '{}' on type {}", name, self.found),
+                self.span,
+            )
+            .with_label("unknown method".to_string()),
+            TypeErrorKind::InvalidKey => Diagnostic::error(
+                "E0211",
+                format!("A value of type {} cannot be a map key", self.found),
+                self.span,
+            )
+            .with_help("map keys are int64, string or bool".to_string()),
+            TypeErrorKind::Redeclared(name) => {
+                Diagnostic::error("E0212", format!("Type '{}' is already declared", name), self.span)
+            }
+            TypeErrorKind::DuplicateMember(name) => Diagnostic::error(
+                "E0213",
+                format!("'{}' is given twice in {}", name, self.found),
+                self.span,
+            ),
+            TypeErrorKind::UnknownField(name) => {
+                Diagnostic::error("E0214", format!("No field '{}' on type {}", name, self.found), self.span)
+                    .with_label("unknown field".to_string())
+            }
+            TypeErrorKind::MissingField(name) => Diagnostic::error(
+                "E0215",
+                format!("Missing field '{}' in {} literal", name, self.found),
+                self.span,
+            )
+            .with_help("every field of a struct needs a value".to_string()),
+            TypeErrorKind::UnknownVariant(name) => {
+                Diagnostic::error("E0216", format!("No variant '{}' in enum {}", name, self.found), self.span)
+                    .with_label("unknown variant".to_string())
+            }
+            TypeErrorKind::WrongPatternCount(name, want, got) => Diagnostic::error(
+                "E0217",
+                format!("Variant '{}' has {} fields but the pattern has {}", name, want, got),
+                self.span,
+            ),
+            TypeErrorKind::NonExhaustive(missing) => {
+                Diagnostic::error("E0218", "Match is not exhaustive".to_string(), self.span)
+                    .with_label(format!("'{}' not covered", missing))
+                    .with_help("add an arm for it, or a '_' arm to match anything else".to_string())
+            }
+            TypeErrorKind::UnreachableArm => {
+                Diagnostic::error("E0219", "Unreachable match arm".to_string(), self.span)
+                    .with_label("every value it matches is matched by an earlier arm".to_string())
+            }
+            TypeErrorKind::NullableValue => {
+                let help = "check it against null with an 'if' first, give it a default with '??' or use '?.' \
+                            to reach into it";
+                let d = Diagnostic::error("E0220", format!("Value of type {} may be null", self.found), self.span)
+                    .with_help(help.to_string());
+                match &self.expected {
+                    Some(t) => d.with_label(format!("expected {}", t)),
+                    None => d,
+                }
+            }
+        }
     }
 }
 
-pub fn ty_of(expr: &Expression, map: HashMap<String, String>) -> Type {
-    match expr {
-        Expression::Integer(_) => Type::Integer,
-        Expression::Float(_) => Type::Float,
-        Expression::Boolean(_) => Type::Boolean,
-        Expression::String(_) => Type::String,
-        Expression::Identifier(name) => {
-            string_to_type(map.get(name).unwrap())
+// does every path through `stmts` end in a return
+fn always_returns(stmts: &[Statement]) -> bool {
+    stmts.iter().any(|stmt| match &stmt.kind {
+        StatementKind::Return(_) => true,
+        StatementKind::Block(stmts) => always_returns(stmts),
+        StatementKind::IfElse(_, s1, s2) => {
+            always_returns(std::slice::from_ref(s1)) && always_returns(std::slice::from_ref(s2))
+        }
+        _ => false,
+    })
+}
+
+// add the name of every variable `stmt` assigns to `names`, including in the
+// functions it declares
+fn assignments(stmt: &Statement, names: &mut Vec<String>) {
+    match &stmt.kind {
+        StatementKind::Expression(e) | StatementKind::Print(e) | StatementKind::Return(Some(e)) => {
+            expression_assignments(e, names)
+        }
+        StatementKind::Let(_, _, e) => expression_assignments(e, names),
+        StatementKind::If(e, s) => {
+            expression_assignments(e, names);
+            assignments(s, names);
+        }
+        StatementKind::While(e, s) => {
+            expression_assignments(e, names);
+            assignments(s, names);
+        }
+        StatementKind::IfElse(e, s1, s2) => {
+            expression_assignments(e, names);
+            assignments(s1, names);
+            assignments(s2, names);
+        }
+        StatementKind::Block(stmts) => stmts.iter().for_each(|s| assignments(s, names)),
+        StatementKind::Function(_, f) => f.body.iter().for_each(|s| assignments(s, names)),
+        StatementKind::Return(None) | StatementKind::Struct(_) | StatementKind::Impl(..) | StatementKind::Enum(_) => (),
+    }
+}
+
+fn expression_assignments(expr: &Expression, names: &mut Vec<String>) {
+    let children: Vec<&Expression> = match &expr.kind {
+        ExpressionKind::Assign(v, e) => {
+            names.push(v.name.clone());
+            vec![e]
+        }
+        ExpressionKind::Prefix(_, e) | ExpressionKind::Field(e, _) | ExpressionKind::SafeField(e, _) => vec![e],
+        ExpressionKind::Infix(a, _, b) | ExpressionKind::Index(a, b) | ExpressionKind::SetField(a, _, b) => {
+            vec![a, b]
+        }
+        ExpressionKind::SetIndex(a, b, c) => vec![a, b, c],
+        ExpressionKind::Call(e, args) | ExpressionKind::Method(e, _, args) | ExpressionKind::SafeMethod(e, _, args) => {
+            std::iter::once(&**e).chain(args).collect()
+        }
+        ExpressionKind::Array(es) | ExpressionKind::Variant(_, _, es) => es.iter().collect(),
+        ExpressionKind::Map(entries) => entries.iter().flat_map(|(k, v)| [k, v]).collect(),
+        ExpressionKind::Slice(e, start, end) => {
+            std::iter::once(e).chain(start.iter()).chain(end.iter()).map(|e| &**e).collect()
+        }
+        ExpressionKind::Instance(_, fields) => fields.iter().map(|(_, e)| e).collect(),
+        ExpressionKind::Match(subject, arms) => std::iter::once(&**subject)
+            .chain(arms.iter().flat_map(|arm| arm.guard.iter().chain([&arm.body])))
+            .collect(),
+        ExpressionKind::Interpolation(fragments) => fragments
+            .iter()
+            .filter_map(|fragment| match fragment {
+                Fragment::Expression(e) => Some(e),
+                Fragment::Text(_) => None,
+            })
+            .collect(),
+        ExpressionKind::Lambda(f) => {
+            f.body.iter().for_each(|s| assignments(s, names));
+            Vec::new()
+        }
+        ExpressionKind::Integer(_) | ExpressionKind::Float(_) | ExpressionKind::String(_) |
+            ExpressionKind::Boolean(_) | ExpressionKind::Identifier(_) | ExpressionKind::Null => Vec::new(),
+    };
+    for child in children {
+        expression_assignments(child, names);
+    }
+}
+
+// the fields of a struct in the order declared, and the signatures of its
+// methods without `self`
+#[derive(Clone, Default)]
+struct StructType {
+    fields: Vec<(String, Type)>,
+    methods: HashMap<String, (Vec<Type>, Type)>,
+}
+
+// a pattern as the match analysis sees it: anything, or a constructor of
+// the matched type with patterns for its fields
+#[derive(Clone, PartialEq)]
+enum Pat {
+    Any,
+    Constructor(Constructor, Vec<Pat>),
+}
+
+#[derive(Clone, PartialEq)]
+enum Constructor {
+    // an enum and the index of one of its variants
+    Variant(String, usize),
+    Boolean(bool),
+    Null,
+    // a number or string, as written
+    Literal(String),
+}
+
+// the rows of `rows` that match constructor `c`, with the patterns for its
+// `arity` fields in front of the rest of the row
+fn specialise(rows: &[Vec<Pat>], c: &Constructor, arity: usize) -> Vec<Vec<Pat>> {
+    rows.iter()
+        .filter_map(|row| {
+            let mut fields = match &row[0] {
+                Pat::Any => vec![Pat::Any; arity],
+                Pat::Constructor(d, fields) if d == c => fields.clone(),
+                Pat::Constructor(..) => return None,
+            };
+            fields.extend_from_slice(&row[1..]);
+            Some(fields)
+        })
+        .collect()
+}
+
+// the rows that match anything in their first column, without it
+fn default_rows(rows: &[Vec<Pat>]) -> Vec<Vec<Pat>> {
+    rows.iter().filter(|row| row[0] == Pat::Any).map(|row| row[1..].to_vec()).collect()
+}
+
+// the constructors at the start of the rows
+fn heads(rows: &[Vec<Pat>]) -> Vec<Constructor> {
+    rows.iter()
+        .filter_map(|row| match &row[0] {
+            Pat::Constructor(c, _) => Some(c.clone()),
+            Pat::Any => None,
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct TypeChecker {
+    // every name in scope, innermost scope last. A `let` in an inner block
+    // shadows an outer one until the block ends
+    scopes: Vec<HashMap<String, Type>>,
+    // return type of the function being checked
+    returns: Option<Type>,
+    structs: HashMap<String, StructType>,
+    // the variants of each enum in the order declared, with their field types
+    enums: HashMap<String, Vec<(String, Vec<Type>)>>,
+    // variables known not to be null here, by the depth of the scope that
+    // declares them and their name, with their types without null
+    narrowed: HashMap<(usize, String), Type>,
+    // variables some function assigns to. A call could make them null at any
+    // point, so they are never narrowed
+    captured: HashSet<(usize, String)>,
+    errors: Vec<TypeError>,
+}
+
+impl TypeChecker {
+    pub fn new() -> TypeChecker {
+        TypeChecker {
+            scopes: vec![HashMap::new()],
+            returns: None,
+            structs: HashMap::new(),
+            enums: HashMap::new(),
+            narrowed: HashMap::new(),
+            captured: HashSet::new(),
+            errors: Vec::new(),
         }
-        Expression::Variable(name) => {
-            string_to_type(map.get(name).unwrap())
+    }
+
+    fn error(&mut self, kind: TypeErrorKind, expected: Option<Type>, found: Type, span: Span) -> Type {
+        self.errors.push(TypeError {
+            kind,
+            expected,
+            found,
+            span,
+        });
+        Type::Error
+    }
+
+    fn annotation(&mut self, ty: &TypeAnnotation, span: Span) -> Type {
+        match ty {
+            TypeAnnotation::Named(name) => match string_to_type(name) {
+                Some(t) => t,
+                None if self.structs.contains_key(name) => Type::Struct(name.clone()),
+                None if self.enums.contains_key(name) => Type::Enum(name.clone()),
+                None => self.error(TypeErrorKind::UnknownType(name.to_string()), None, Type::Error, span),
+            },
+            TypeAnnotation::Generic(name, args) => match (name.as_str(), args.as_slice()) {
+                ("array", [element]) => Type::Array(Box::new(self.annotation(element, span))),
+                ("map", [key, value]) => {
+                    let key = self.annotation(key, span);
+                    let key = self.key(key, span);
+                    Type::Map(Box::new(key), Box::new(self.annotation(value, span)))
+                }
+                _ => self.error(TypeErrorKind::UnknownType(ty.to_string()), None, Type::Error, span),
+            },
+            TypeAnnotation::Function(params, ret) => {
+                let params = params.iter().map(|p| self.annotation(p, span)).collect();
+                Type::Function(params, Box::new(self.annotation(ret, span)))
+            }
+            TypeAnnotation::Optional(ty) => optional(self.annotation(ty, span)),
         }
-        Expression::Assign(name, e) => {
-            let ty = ty_of(e, map.clone());
-            match (string_to_type(map.get(name).unwrap()), ty) {
-                (Type::Integer, Type::Integer) => Type::Integer,
-                (Type::Float, Type::Float) => Type::Float,
-                (Type::String, Type::String) => Type::String,
-                (Type::Boolean, Type::Boolean) => Type::Boolean,
-                (Type::Null, Type::Null) => Type::Null,
-                _ => panic!("Type mismatch"),
+    }
+
+    fn declare(&mut self, name: &str, ty: Type) {
+        self.narrowed.remove(&(self.scopes.len() - 1, name.to_string()));
+        self.scopes.last_mut().unwrap().insert(name.to_string(), ty);
+    }
+
+    // the depth of the scope declaring `name` and the name, which together
+    // tell variables apart
+    fn binding(&self, name: &str) -> Option<(usize, String)> {
+        let depth = self.scopes.iter().rposition(|scope| scope.contains_key(name))?;
+        Some((depth, name.to_string()))
+    }
+
+    fn variable(&mut self, name: &str, span: Span) -> Type {
+        match self.scopes.iter().rev().find_map(|scope| scope.get(name)).cloned() {
+            Some(ty) => ty,
+            None => self.error(
+                TypeErrorKind::UndefinedVariable(name.to_string()),
+                None,
+                Type::Error,
+                span,
+            ),
+        }
+    }
+
+    // check `found` can be used where `expected` is required
+    fn expect(&mut self, expected: Type, found: Type, span: Span) -> Type {
+        match &found {
+            _ if fits(&expected, &found) => expected,
+            Type::Optional(t) if fits(&expected, t) => {
+                self.error(TypeErrorKind::NullableValue, Some(expected), found, span)
+            }
+            _ => self.error(TypeErrorKind::Mismatch, Some(expected), found, span),
+        }
+    }
+
+    // `ty` without null, for a value that cannot be null where it is used
+    fn non_null(&mut self, ty: Type, span: Span) -> Type {
+        match ty {
+            Type::Optional(t) => {
+                self.error(TypeErrorKind::NullableValue, None, Type::Optional(t.clone()), span);
+                *t
+            }
+            t => t,
+        }
+    }
+
+    fn condition(&mut self, e: &Expression) {
+        match self.ty_of(e) {
+            Type::Boolean | Type::Error => (),
+            Type::Optional(t) if *t == Type::Boolean => {
+                self.error(TypeErrorKind::NullableValue, Some(Type::Boolean), Type::Optional(t), e.span);
+            }
+            t => {
+                self.error(TypeErrorKind::NonBooleanCondition, Some(Type::Boolean), t, e.span);
+            }
+        }
+    }
+
+    // the variables `cond` shows are not null when it is `truth`, with their
+    // types without null. `a && b` being true means both sides ran and were
+    // true, and `a || b` being false that both were false
+    fn narrowing(&self, cond: &Expression, truth: bool) -> Vec<((usize, String), Type)> {
+        match &cond.kind {
+            ExpressionKind::Infix(left, op @ (Token::BangEqual | Token::DoubleEqual), right)
+                if (*op == Token::BangEqual) == truth => {
+                let v = match (&left.kind, &right.kind) {
+                    (ExpressionKind::Identifier(v), ExpressionKind::Null) |
+                        (ExpressionKind::Null, ExpressionKind::Identifier(v)) => v,
+                    _ => return Vec::new(),
+                };
+                let Some(binding) = self.binding(&v.name) else {
+                    return Vec::new();
+                };
+                match &self.scopes[binding.0][&v.name] {
+                    Type::Optional(t) if !self.captured.contains(&binding) => vec![(binding, (**t).clone())],
+                    _ => Vec::new(),
+                }
+            }
+            ExpressionKind::Infix(left, Token::And, right) if truth => {
+                [self.narrowing(left, truth), self.narrowing(right, truth)].concat()
+            }
+            ExpressionKind::Infix(left, Token::Or, right) if !truth => {
+                [self.narrowing(left, truth), self.narrowing(right, truth)].concat()
+            }
+            ExpressionKind::Prefix(Token::Bang, e) => self.narrowing(e, !truth),
+            _ => Vec::new(),
+        }
+    }
+
+    // check `f` with the variables in `narrowings` known not to be null, then
+    // forget them again
+    fn narrow<T>(&mut self, narrowings: Vec<((usize, String), Type)>, f: impl FnOnce(&mut Self) -> T) -> T {
+        let outer = self.narrowed.clone();
+        self.narrowed.extend(narrowings);
+        let result = f(self);
+        self.restore(outer);
+        result
+    }
+
+    // go back to the narrowings from before, less any that an assignment
+    // since has ended
+    fn restore(&mut self, mut outer: HashMap<(usize, String), Type>) {
+        outer.retain(|binding, _| self.narrowed.contains_key(binding));
+        self.narrowed = outer;
+    }
+
+    // a variable assigned where it cannot be followed, in a loop or a
+    // function, may be null whenever it is next used
+    fn forget(&mut self, names: Vec<String>) {
+        for name in names {
+            if let Some(binding) = self.binding(&name) {
+                self.narrowed.remove(&binding);
             }
         }
-        Expression::Infix(e1, op, e2) => {
-            match op {
-                Token::BangEqual | Token::DoubleEqual | Token::Greater | Token::GreaterEqual |
-                    Token::Less | Token::LessEqual | Token::And | Token::Or | Token::Xor => {
-                    match (ty_of(e1, map.clone()), ty_of(e2, map.clone())) {
-                        (Type::Boolean, Type::Boolean) => Type::Boolean,
-                        (Type::Integer, Type::Integer) => Type::Boolean,
-                        (Type::Float, Type::Float) => Type::Boolean,
-                        (Type::String, Type::String) => Type::Boolean,
-                        _ => panic!("Type mismatch for logical infix operator"),
+    }
+
+    pub fn ty_of(&mut self, expr: &Expression) -> Type {
+        match &expr.kind {
+            ExpressionKind::Integer(_) => Type::Integer,
+            ExpressionKind::Float(_) => Type::Float,
+            ExpressionKind::Boolean(_) => Type::Boolean,
+            ExpressionKind::String(_) => Type::String,
+            // any value can be shown, so only the expressions themselves are
+            // checked
+            ExpressionKind::Interpolation(fragments) => {
+                for fragment in fragments {
+                    if let Fragment::Expression(e) = fragment {
+                        self.ty_of(e);
+                    }
+                }
+                Type::String
+            }
+            ExpressionKind::Identifier(v) => {
+                let ty = self.variable(&v.name, expr.span);
+                let narrowed = self.binding(&v.name).and_then(|binding| self.narrowed.get(&binding));
+                narrowed.cloned().unwrap_or(ty)
+            }
+            // checked against the declared type, a value that may be null
+            // ends any narrowing of the variable
+            ExpressionKind::Assign(v, e) => {
+                let declared = self.variable(&v.name, expr.span);
+                let ty = self.value(&declared, e);
+                if let Some(binding) = self.binding(&v.name) {
+                    if !self.narrowed.get(&binding).is_some_and(|t| fits(t, &ty)) {
+                        self.narrowed.remove(&binding);
                     }
                 }
+                self.expect(declared, ty, e.span)
+            }
+            ExpressionKind::Prefix(op, e) => {
+                let ty = self.ty_of(e);
+                match (op, self.non_null(ty, e.span)) {
+                    (_, Type::Error) => Type::Error,
+                    (Token::Minus, Type::Integer) => Type::Integer,
+                    (Token::Minus, Type::Float) => Type::Float,
+                    (Token::Bang, Type::Boolean) => Type::Boolean,
+                    (_, t) => self.error(TypeErrorKind::InvalidOperands(op.clone()), None, t, expr.span),
+                }
+            }
+            ExpressionKind::Infix(value, Token::DoubleQuestion, default) => self.default(value, default),
+            ExpressionKind::Infix(e1, op, e2) => {
+                let left = self.ty_of(e1);
+                // the right of `&&` only runs when the left is true, and the
+                // right of `||` when it is false
+                let right = match op {
+                    Token::And | Token::Or => {
+                        let narrowings = self.narrowing(e1, *op == Token::And);
+                        self.narrow(narrowings, |checker| checker.ty_of(e2))
+                    }
+                    _ => self.ty_of(e2),
+                };
+                if left == Type::Error || right == Type::Error {
+                    return Type::Error;
+                }
+                // only `==` and `!=` take a value that may be null
+                let (left, right) = match op {
+                    Token::BangEqual | Token::DoubleEqual => (left, right),
+                    _ => (self.non_null(left, e1.span), self.non_null(right, e2.span)),
+                };
+                match op {
+                    Token::BangEqual | Token::DoubleEqual if nullable(&left) || nullable(&right) => {
+                        match merge(&left, &right) {
+                            Some(_) => Type::Boolean,
+                            None => self.error(TypeErrorKind::Mismatch, Some(left), right, e2.span),
+                        }
+                    }
+                    Token::BangEqual | Token::DoubleEqual
+                        if matches!(left, Type::Array(_) | Type::Map(..) | Type::Struct(_) | Type::Enum(_)) => {
+                        match merge(&left, &right) {
+                            Some(_) => Type::Boolean,
+                            None => self.error(TypeErrorKind::Mismatch, Some(left), right, e2.span),
+                        }
+                    }
+                    Token::BangEqual | Token::DoubleEqual | Token::Greater | Token::GreaterEqual |
+                        Token::Less | Token::LessEqual | Token::And | Token::Or | Token::Xor => {
+                        match (left, right) {
+                            (Type::Boolean, Type::Boolean) => Type::Boolean,
+                            (Type::Integer, Type::Integer) => Type::Boolean,
+                            (Type::Float, Type::Float) => Type::Boolean,
+                            (Type::String, Type::String) => Type::Boolean,
+                            (l, r) => self.error(TypeErrorKind::Mismatch, Some(l), r, e2.span),
+                        }
+                    }
+
+                    Token::Plus => {
+                        match (left, right) {
+                            (Type::Integer, Type::Integer) => Type::Integer,
+                            (Type::Float, Type::Float) => Type::Float,
+                            (Type::String, Type::String) => Type::String,
+                            (l @ Type::Array(_), r) => match merge(&l, &r) {
+                                Some(t) => t,
+                                None => self.error(TypeErrorKind::Mismatch, Some(l), r, e2.span),
+                            },
+                            (l, r) if l == r => {
+                                self.error(TypeErrorKind::InvalidOperands(op.clone()), None, l, expr.span)
+                            }
+                            (l, r) => self.error(TypeErrorKind::Mismatch, Some(l), r, e2.span),
+                        }
+                    }
 
-                Token::Plus => {
-                    match (ty_of(e1, map.clone()), ty_of(e2, map.clone())) {
-                        (Type::Integer, Type::Integer) => Type::Integer,
-                        (Type::Float, Type::Float) => Type::Float,
-                        (Type::String, Type::String) => Type::String,
-                        _ => panic!("Type mismatch for infix operator +"),
+                    Token::Minus | Token::Times | Token::Divide => {
+                        match (left, right) {
+                            (Type::Integer, Type::Integer) => Type::Integer,
+                            (Type::Float, Type::Float) => Type::Float,
+                            (l, r) if l == r => {
+                                self.error(TypeErrorKind::InvalidOperands(op.clone()), None, l, expr.span)
+                            }
+                            (l, r) => self.error(TypeErrorKind::Mismatch, Some(l), r, e2.span),
+                        }
+                    }
+                    _ => self.error(TypeErrorKind::InvalidOperands(op.clone()), None, left, expr.span),
+                }
+            }
+            ExpressionKind::Call(callee, args) => {
+                let callee_ty = self.ty_of(callee);
+                let callee_ty = self.non_null(callee_ty, callee.span);
+                match callee_ty {
+                    Type::Function(params, ret) => {
+                        self.arguments(params, args, expr.span);
+                        *ret
+                    }
+                    Type::Error => {
+                        for arg in args {
+                            self.ty_of(arg);
+                        }
+                        Type::Error
+                    }
+                    t => {
+                        for arg in args {
+                            self.ty_of(arg);
+                        }
+                        self.error(TypeErrorKind::NotCallable, None, t, callee.span)
+                    }
+                }
+            }
+            ExpressionKind::Method(receiver, name, args) => {
+                let receiver_ty = self.ty_of(receiver);
+                let receiver_ty = self.non_null(receiver_ty, receiver.span);
+                self.method_call(receiver_ty, name, args, expr.span)
+            }
+            // null if the receiver may be
+            ExpressionKind::SafeMethod(receiver, name, args) => match self.ty_of(receiver) {
+                Type::Optional(t) => optional(self.method_call(*t, name, args, expr.span)),
+                t => self.method_call(t, name, args, expr.span),
+            },
+            ExpressionKind::Array(elements) => {
+                let mut element_ty = Type::Unknown;
+                for element in elements {
+                    let ty = self.ty_of(element);
+                    match merge(&element_ty, &ty) {
+                        Some(t) => element_ty = t,
+                        None => {
+                            self.error(TypeErrorKind::Mismatch, Some(element_ty.clone()), ty, element.span);
+                        }
+                    }
+                }
+                Type::Array(Box::new(element_ty))
+            }
+            ExpressionKind::Map(entries) => {
+                let (mut key_ty, mut value_ty) = (Type::Unknown, Type::Unknown);
+                for (key, value) in entries {
+                    for (e, merged) in [(key, &mut key_ty), (value, &mut value_ty)] {
+                        let ty = self.ty_of(e);
+                        match merge(merged, &ty) {
+                            Some(t) => *merged = t,
+                            None => {
+                                self.error(TypeErrorKind::Mismatch, Some(merged.clone()), ty, e.span);
+                            }
+                        }
+                    }
+                }
+                if let Some((key, _)) = entries.first() {
+                    key_ty = self.key(key_ty, key.span);
+                }
+                Type::Map(Box::new(key_ty), Box::new(value_ty))
+            }
+            ExpressionKind::Index(target, index) => self.element(target, index),
+            ExpressionKind::Instance(name, fields) => self.instance(name, fields, expr.span),
+            ExpressionKind::Field(target, field) => {
+                let ty = self.ty_of(target);
+                let ty = self.non_null(ty, target.span);
+                self.field(ty, field, expr.span)
+            }
+            ExpressionKind::SafeField(target, field) => match self.ty_of(target) {
+                Type::Optional(t) => optional(self.field(*t, field, expr.span)),
+                t => self.field(t, field, expr.span),
+            },
+            ExpressionKind::SetField(target, field, value) => {
+                let ty = self.ty_of(target);
+                let ty = self.non_null(ty, target.span);
+                let field_ty = self.field(ty, field, expr.span);
+                let value_ty = self.value(&field_ty, value);
+                self.expect(field_ty, value_ty, value.span)
+            }
+            ExpressionKind::Slice(target, start, end) => {
+                let ty = self.ty_of(target);
+                let ty = self.non_null(ty, target.span);
+                for bound in [start, end].into_iter().flatten() {
+                    self.index(bound);
+                }
+                match ty {
+                    Type::Array(_) | Type::Error => ty,
+                    t => self.error(TypeErrorKind::NotIndexable, None, t, target.span),
+                }
+            }
+            ExpressionKind::SetIndex(target, index, value) => {
+                let element = self.element(target, index);
+                let value_ty = self.value(&element, value);
+                self.expect(element, value_ty, value.span)
+            }
+            ExpressionKind::Variant(name, variant, args) => match self.lookup_variant(name, variant, expr.span) {
+                Some(index) => {
+                    let fields = self.enums[name][index].1.clone();
+                    self.arguments(fields, args, expr.span);
+                    Type::Enum(name.clone())
+                }
+                None => {
+                    for arg in args {
+                        self.ty_of(arg);
                     }
+                    Type::Error
+                }
+            },
+            ExpressionKind::Match(subject, arms) => self.check_match(subject, arms, expr.span),
+            ExpressionKind::Lambda(f) => {
+                let (params, ret) = self.signature(f);
+                self.check_body(f, params.clone(), ret.clone());
+                Type::Function(params, Box::new(ret))
+            }
+            ExpressionKind::Null => Type::Null,
+        }
+    }
+
+    // check the arguments of a call against the parameter types
+    fn arguments(&mut self, params: Vec<Type>, args: &[Expression], span: Span) {
+        for (i, arg) in args.iter().enumerate() {
+            match params.get(i) {
+                Some(param) => {
+                    let arg_ty = self.value(param, arg);
+                    self.expect(param.clone(), arg_ty, arg.span);
+                }
+                None => {
+                    self.ty_of(arg);
+                }
+            }
+        }
+        if params.len() != args.len() {
+            let kind = TypeErrorKind::WrongArgumentCount(params.len(), args.len());
+            self.error(kind, None, Type::Error, span);
+        }
+    }
+
+    // the type of `e` where a value of type `expected` is wanted. An array or
+    // map literal is new, nothing else can see it yet, so it can take its
+    // element types from `expected`: `[1, 2]` is an `array<int64?>` if one
+    // is wanted
+    fn value(&mut self, expected: &Type, e: &Expression) -> Type {
+        match (&e.kind, expected) {
+            (ExpressionKind::Array(_) | ExpressionKind::Map(_), Type::Optional(t)) => self.value(t, e),
+            (ExpressionKind::Array(elements), Type::Array(t)) => {
+                for element in elements {
+                    let ty = self.value(t, element);
+                    self.expect((**t).clone(), ty, element.span);
+                }
+                expected.clone()
+            }
+            (ExpressionKind::Map(entries), Type::Map(k, v)) => {
+                for (key, value) in entries {
+                    let ty = self.value(k, key);
+                    self.expect((**k).clone(), ty, key.span);
+                    let ty = self.value(v, value);
+                    self.expect((**v).clone(), ty, value.span);
+                }
+                expected.clone()
+            }
+            _ => self.ty_of(e),
+        }
+    }
+
+    fn index(&mut self, index: &Expression) {
+        let ty = self.ty_of(index);
+        self.expect(Type::Integer, ty, index.span);
+    }
+
+    // the type of `target[index]`, an array takes an int64 index and a map
+    // a key
+    fn element(&mut self, target: &Expression, index: &Expression) -> Type {
+        let ty = self.ty_of(target);
+        match self.non_null(ty, target.span) {
+            Type::Array(t) => {
+                self.index(index);
+                *t
+            }
+            Type::Map(k, v) => {
+                let ty = self.ty_of(index);
+                self.expect(*k, ty, index.span);
+                *v
+            }
+            Type::Error => {
+                self.ty_of(index);
+                Type::Error
+            }
+            t => {
+                self.ty_of(index);
+                self.error(TypeErrorKind::NotIndexable, None, t, target.span)
+            }
+        }
+    }
+
+    // the type of calling the method `name` on a value of type `ty`
+    fn method_call(&mut self, ty: Type, name: &str, args: &[Expression], span: Span) -> Type {
+        match self.method(&ty, name) {
+            Some((params, ret)) => {
+                self.arguments(params, args, span);
+                ret
+            }
+            None => {
+                for arg in args {
+                    self.ty_of(arg);
+                }
+                match ty {
+                    Type::Error => Type::Error,
+                    ty => self.error(TypeErrorKind::UnknownMethod(name.to_string()), None, ty, span),
+                }
+            }
+        }
+    }
+
+    // `value ?? default` has the value's type without null, unless the
+    // default may be null too
+    fn default(&mut self, value: &Expression, default: &Expression) -> Type {
+        let ty = match self.ty_of(value) {
+            Type::Optional(t) => *t,
+            t => t,
+        };
+        let default_ty = self.ty_of(default);
+        if ty == Type::Null {
+            return default_ty;
+        }
+        match merge(&ty, &default_ty) {
+            Some(t) => t,
+            None => self.error(TypeErrorKind::Mismatch, Some(ty), default_ty, default.span),
+        }
+    }
+
+    fn method(&self, ty: &Type, name: &str) -> Option<(Vec<Type>, Type)> {
+        match ty {
+            Type::Struct(s) => self.structs.get(s)?.methods.get(name).cloned(),
+            _ => builtin_method(ty, name),
+        }
+    }
+
+    // the type of the field `name` of a value of type `ty`
+    fn field(&mut self, ty: Type, name: &str, span: Span) -> Type {
+        let found = match &ty {
+            Type::Struct(s) => self.structs.get(s).and_then(|s| s.fields.iter().find(|(f, _)| f == name)),
+            _ => None,
+        };
+        match found {
+            Some((_, t)) => t.clone(),
+            None if ty == Type::Error => Type::Error,
+            None => self.error(TypeErrorKind::UnknownField(name.to_string()), None, ty, span),
+        }
+    }
+
+    // a struct literal must give every field exactly once
+    fn instance(&mut self, name: &str, fields: &[(String, Expression)], span: Span) -> Type {
+        let declared = match self.structs.get(name) {
+            Some(s) => s.fields.clone(),
+            None => {
+                for (_, value) in fields {
+                    self.ty_of(value);
+                }
+                return self.error(TypeErrorKind::UnknownType(name.to_string()), None, Type::Error, span);
+            }
+        };
+        let ty = Type::Struct(name.to_string());
+        for (i, (field, value)) in fields.iter().enumerate() {
+            let declared = declared.iter().find(|(f, _)| f == field).map(|(_, t)| t);
+            let value_ty = match declared {
+                Some(t) => self.value(t, value),
+                None => self.ty_of(value),
+            };
+            if fields[..i].iter().any(|(f, _)| f == field) {
+                self.error(TypeErrorKind::DuplicateMember(field.clone()), None, ty.clone(), value.span);
+            }
+            match declared {
+                Some(t) => {
+                    self.expect(t.clone(), value_ty, value.span);
+                }
+                None => {
+                    self.error(TypeErrorKind::UnknownField(field.clone()), None, ty.clone(), value.span);
+                }
+            }
+        }
+        for (field, _) in &declared {
+            if !fields.iter().any(|(f, _)| f == field) {
+                self.error(TypeErrorKind::MissingField(field.clone()), None, ty.clone(), span);
+            }
+        }
+        ty
+    }
+
+    // registered before its fields are read, so a field can hold the struct
+    // itself, e.g. `children: array<Node>`
+    fn declare_struct(&mut self, s: &Struct) {
+        if self.structs.contains_key(&s.name) || self.enums.contains_key(&s.name) {
+            self.error(TypeErrorKind::Redeclared(s.name.clone()), None, Type::Error, s.span);
+            return;
+        }
+        self.structs.insert(s.name.clone(), StructType::default());
+        let ty = Type::Struct(s.name.clone());
+        let mut fields: Vec<(String, Type)> = Vec::new();
+        for field in &s.fields {
+            let field_ty = self.annotation(&field.ty, field.span);
+            if fields.iter().any(|(f, _)| *f == field.name) {
+                self.error(TypeErrorKind::DuplicateMember(field.name.clone()), None, ty.clone(), field.span);
+            }
+            fields.push((field.name.clone(), field_ty));
+        }
+        self.structs.get_mut(&s.name).unwrap().fields = fields;
+    }
+
+    // the index of `variant` in the enum `name`
+    fn lookup_variant(&mut self, name: &str, variant: &str, span: Span) -> Option<usize> {
+        let Some(variants) = self.enums.get(name) else {
+            self.error(TypeErrorKind::UnknownType(name.to_string()), None, Type::Error, span);
+            return None;
+        };
+        let index = variants.iter().position(|(v, _)| v == variant);
+        if index.is_none() {
+            let ty = Type::Enum(name.to_string());
+            self.error(TypeErrorKind::UnknownVariant(variant.to_string()), None, ty, span);
+        }
+        index
+    }
+
+    // registered before its variants are read, so a variant can hold the enum
+    // itself, e.g. `Node(int64, List)`
+    fn declare_enum(&mut self, e: &Enum) {
+        if self.structs.contains_key(&e.name) || self.enums.contains_key(&e.name) {
+            self.error(TypeErrorKind::Redeclared(e.name.clone()), None, Type::Error, e.span);
+            return;
+        }
+        self.enums.insert(e.name.clone(), Vec::new());
+        let ty = Type::Enum(e.name.clone());
+        let mut variants: Vec<(String, Vec<Type>)> = Vec::new();
+        for variant in &e.variants {
+            let fields = variant.fields.iter().map(|f| self.annotation(f, variant.span)).collect();
+            if variants.iter().any(|(v, _)| *v == variant.name) {
+                self.error(TypeErrorKind::DuplicateMember(variant.name.clone()), None, ty.clone(), variant.span);
+            }
+            variants.push((variant.name.clone(), fields));
+        }
+        self.enums.insert(e.name.clone(), variants);
+    }
+
+    // every arm is checked with the names its pattern binds, and all arms
+    // must give the same type. Unless a pattern is wrong, the arms are also
+    // checked to cover every value and to each match something new
+    fn check_match(&mut self, subject: &Expression, arms: &[Arm], span: Span) -> Type {
+        let ty = self.ty_of(subject);
+        let mut result = Type::Unknown;
+        let mut pats = Vec::new();
+        // once a `null` arm is passed a name binds the rest
+        let mut null = false;
+        for arm in arms {
+            self.scopes.push(HashMap::new());
+            let arm_ty = match (&arm.pattern.kind, &ty) {
+                (PatternKind::Binding(_), Type::Optional(t)) if null => (**t).clone(),
+                _ => ty.clone(),
+            };
+            pats.push(self.pattern(&arm.pattern, &arm_ty));
+            null |= arm.pattern.kind == PatternKind::Null && arm.guard.is_none();
+            if let Some(guard) = &arm.guard {
+                self.condition(guard);
+            }
+            let body = self.ty_of(&arm.body);
+            self.scopes.pop();
+            match merge(&result, &body) {
+                Some(t) => result = t,
+                None => {
+                    self.error(TypeErrorKind::Mismatch, Some(result.clone()), body, arm.body.span);
                 }
+            }
+        }
+
+        if let Some(pats) = pats.into_iter().collect::<Option<Vec<Pat>>>() {
+            if ty != Type::Error {
+                self.coverage(arms, pats, ty, span);
+            }
+        }
+        if result == Type::Unknown { Type::Null } else { result }
+    }
 
-                Token::Minus | Token::Times | Token::Divide => {
-                    match (ty_of(e1, map.clone()), ty_of(e2, map.clone())) {
-                        (Type::Integer, Type::Integer) => Type::Integer,
-                        (Type::Float, Type::Float) => Type::Float,
-                        _ => panic!("Invalid types for infix operator"),
+    // report arms that can never be taken and values no arm matches. A
+    // guarded arm may not match, so it covers nothing
+    fn coverage(&mut self, arms: &[Arm], pats: Vec<Pat>, ty: Type, span: Span) {
+        let types = [ty];
+        let mut rows: Vec<Vec<Pat>> = Vec::new();
+        for (arm, pat) in arms.iter().zip(pats) {
+            let row = vec![pat];
+            if !self.useful(&rows, &row, &types) {
+                self.error(TypeErrorKind::UnreachableArm, None, Type::Error, arm.pattern.span);
+            }
+            if arm.guard.is_none() {
+                rows.push(row);
+            }
+        }
+        if let Some(missing) = self.missing(&rows, &types) {
+            let missing = self.show(&missing[0]);
+            self.error(TypeErrorKind::NonExhaustive(missing), None, Type::Error, span);
+        }
+    }
+
+    // check a pattern against the type it matches, declaring the names it
+    // binds. None if the pattern is wrong
+    fn pattern(&mut self, pattern: &Pattern, ty: &Type) -> Option<Pat> {
+        let (literal, c) = match &pattern.kind {
+            PatternKind::Wildcard => return Some(Pat::Any),
+            PatternKind::Binding(v) => {
+                self.declare(&v.name, ty.clone());
+                return Some(Pat::Any);
+            }
+            PatternKind::Integer(i) => (Type::Integer, Constructor::Literal(i.to_string())),
+            PatternKind::Float(f) => (Type::Float, Constructor::Literal(format!("{:?}", f))),
+            PatternKind::String(s) => (Type::String, Constructor::Literal(format!("{:?}", s))),
+            PatternKind::Boolean(b) => (Type::Boolean, Constructor::Boolean(*b)),
+            PatternKind::Null => (Type::Null, Constructor::Null),
+            PatternKind::Variant(name, variant, patterns) => {
+                let index = self.lookup_variant(name, variant, pattern.span);
+                let fields = match index {
+                    Some(index) => self.enums[name][index].1.clone(),
+                    None => Vec::new(),
+                };
+                let mut ok = index.is_some();
+                if ok && !fits(ty, &Type::Enum(name.clone())) {
+                    self.error(TypeErrorKind::Mismatch, Some(ty.clone()), Type::Enum(name.clone()), pattern.span);
+                    ok = false;
+                }
+                if ok && fields.len() != patterns.len() {
+                    let kind = TypeErrorKind::WrongPatternCount(variant.clone(), fields.len(), patterns.len());
+                    self.error(kind, None, Type::Error, pattern.span);
+                    ok = false;
+                }
+                // the fields are still checked so their names are declared
+                let mut subpatterns = Vec::new();
+                for (i, p) in patterns.iter().enumerate() {
+                    let field = fields.get(i).cloned().unwrap_or(Type::Error);
+                    match self.pattern(p, &field) {
+                        Some(p) => subpatterns.push(p),
+                        None => ok = false,
                     }
                 }
-                _ => panic!("Invalid infix operator"),
+                let c = Constructor::Variant(name.clone(), index?);
+                return ok.then_some(Pat::Constructor(c, subpatterns));
             }
+        };
+        if !fits(ty, &literal) {
+            self.error(TypeErrorKind::Mismatch, Some(ty.clone()), literal, pattern.span);
+            return None;
         }
-        Expression::Null => Type::Null,
-        _ => panic!("Not implemented"),
+        Some(Pat::Constructor(c, Vec::new()))
     }
-}
 
-fn typecheck_statement(stmt: &Statement, map: HashMap<String, String>)  -> Type {
-    match stmt {
-        Statement::Expression(expr) => ty_of(expr, map.clone()),
-        Statement::Print(e) => ty_of(e, map.clone()),
-        Statement::If(e, s) => {
-            if ty_of(e, map.clone()) != Type::Boolean {
-                panic!("Condition must be boolean")
+    // every constructor of `ty`, None for types with too many values to list
+    fn constructors(&self, ty: &Type) -> Option<Vec<Constructor>> {
+        match ty {
+            Type::Enum(name) => {
+                let count = self.enums.get(name)?.len();
+                Some((0..count).map(|i| Constructor::Variant(name.clone(), i)).collect())
+            }
+            Type::Boolean => Some(vec![Constructor::Boolean(true), Constructor::Boolean(false)]),
+            Type::Null => Some(vec![Constructor::Null]),
+            Type::Optional(t) => {
+                let mut all = self.constructors(t)?;
+                all.push(Constructor::Null);
+                Some(all)
             }
-            typecheck_statement(s, map.clone())
+            _ => None,
         }
-        Statement::IfElse(e, s1, s2) => {
-            if ty_of(e, map.clone()) != Type::Boolean {
-                panic!("Condition must be boolean")
+    }
+
+    fn fields(&self, c: &Constructor) -> Vec<Type> {
+        match c {
+            Constructor::Variant(name, index) => self.enums[name][*index].1.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    // could a value of `types` match `row` but none of `rows`
+    fn useful(&self, rows: &[Vec<Pat>], row: &[Pat], types: &[Type]) -> bool {
+        let Some((ty, rest)) = types.split_first() else {
+            return rows.is_empty();
+        };
+        match &row[0] {
+            Pat::Constructor(c, fields) => {
+                let mut types = self.fields(c);
+                types.extend_from_slice(rest);
+                let mut next = fields.clone();
+                next.extend_from_slice(&row[1..]);
+                self.useful(&specialise(rows, c, fields.len()), &next, &types)
             }
-            let t1 = typecheck_statement(s1, map.clone());
-            let t2 = typecheck_statement(s2, map.clone());
-            if t1 == t2 {
-                t1
-            } else {
-                panic!("Type mismatch: {:?} and {:?}", t1, t2)
+            Pat::Any => match self.constructors(ty) {
+                Some(all) if all.iter().all(|c| heads(rows).contains(c)) => all.iter().any(|c| {
+                    let mut types = self.fields(c);
+                    let arity = types.len();
+                    types.extend_from_slice(rest);
+                    let mut next = vec![Pat::Any; arity];
+                    next.extend_from_slice(&row[1..]);
+                    self.useful(&specialise(rows, c, arity), &next, &types)
+                }),
+                _ => self.useful(&default_rows(rows), &row[1..], rest),
+            },
+        }
+    }
+
+    // a row of values of `types` that none of `rows` match, None if they
+    // cover everything
+    fn missing(&self, rows: &[Vec<Pat>], types: &[Type]) -> Option<Vec<Pat>> {
+        let Some((ty, rest)) = types.split_first() else {
+            return if rows.is_empty() { Some(Vec::new()) } else { None };
+        };
+        let used = heads(rows);
+        match self.constructors(ty) {
+            Some(all) if all.iter().all(|c| used.contains(c)) => all.into_iter().find_map(|c| {
+                let mut types = self.fields(&c);
+                let arity = types.len();
+                types.extend_from_slice(rest);
+                let mut witness = self.missing(&specialise(rows, &c, arity), &types)?;
+                let tail = witness.split_off(arity);
+                let mut row = vec![Pat::Constructor(c, witness)];
+                row.extend(tail);
+                Some(row)
+            }),
+            all => {
+                let mut witness = self.missing(&default_rows(rows), rest)?;
+                // name a constructor no row starts with if there is one
+                let head = match all.and_then(|all| all.into_iter().find(|c| !used.contains(c))) {
+                    Some(c) => {
+                        let arity = self.fields(&c).len();
+                        Pat::Constructor(c, vec![Pat::Any; arity])
+                    }
+                    None => Pat::Any,
+                };
+                witness.insert(0, head);
+                Some(witness)
             }
         }
-        Statement::While(e, s) => {
-            if ty_of(e, map.clone()) != Type::Boolean {
-                panic!("Condition must be boolean")
+    }
+
+    // a pattern from the analysis written as source
+    fn show(&self, pat: &Pat) -> String {
+        match pat {
+            Pat::Any => "_".to_string(),
+            Pat::Constructor(Constructor::Variant(name, index), fields) => {
+                let variant = format!("{}::{}", name, self.enums[name][*index].0);
+                if fields.is_empty() {
+                    return variant;
+                }
+                let fields: Vec<String> = fields.iter().map(|f| self.show(f)).collect();
+                format!("{}({})", variant, fields.join(", "))
             }
-            typecheck_statement(s, map.clone())
+            Pat::Constructor(Constructor::Boolean(b), _) => b.to_string(),
+            Pat::Constructor(Constructor::Null, _) => "null".to_string(),
+            Pat::Constructor(Constructor::Literal(s), _) => s.clone(),
+        }
+    }
+
+    // every signature is known before any body is checked, so methods can
+    // call each other
+    fn check_impl(&mut self, name: &str, methods: &[Rc<Function>], span: Span) {
+        if !self.structs.contains_key(name) {
+            self.error(TypeErrorKind::UnknownType(name.to_string()), None, Type::Error, span);
+            return;
         }
-        Statement::Let(_, s, e) => {
-            if ty_of(e, map.clone()) == string_to_type(s) {
-                ty_of(e, map.clone())
+        let ty = Type::Struct(name.to_string());
+        let mut signatures = Vec::new();
+        for method in methods {
+            let (params, ret) = self.signature(method);
+            let s = self.structs.get_mut(name).unwrap();
+            if s.methods.contains_key(&method.name) {
+                self.error(TypeErrorKind::DuplicateMember(method.name.clone()), None, ty.clone(), method.span);
             } else {
-                panic!("Type mismatch: {:?} and {:?}", ty_of(e, map.clone()), string_to_type(s))
+                s.methods.insert(method.name.clone(), (params[1..].to_vec(), ret.clone()));
+            }
+            signatures.push((params, ret));
+        }
+        for (method, (params, ret)) in methods.iter().zip(signatures) {
+            self.check_body(method, params, ret);
+        }
+    }
+
+    // `ty` if values of it can be map keys
+    fn key(&mut self, ty: Type, span: Span) -> Type {
+        match ty {
+            Type::Integer | Type::String | Type::Boolean | Type::Unknown | Type::Error => ty,
+            t => self.error(TypeErrorKind::InvalidKey, None, t, span),
+        }
+    }
+
+    fn signature(&mut self, f: &Function) -> (Vec<Type>, Type) {
+        let params = f.params.iter().map(|p| self.annotation(&p.ty, p.span)).collect();
+        let ret = self.annotation(&f.return_type, f.span);
+        (params, ret)
+    }
+
+    fn check_function(&mut self, f: &Function) {
+        let (params, ret) = self.signature(f);
+
+        // declared before the body is checked so the function can recurse
+        let ty = Type::Function(params.clone(), Box::new(ret.clone()));
+        self.declare(&f.name, ty);
+        self.check_body(f, params, ret);
+    }
+
+    // the body can run at any later point, where what was known about the
+    // variables around it when it was declared need not hold
+    fn check_body(&mut self, f: &Function, params: Vec<Type>, ret: Type) {
+        let scope = f.params.iter().map(|p| p.name.clone()).zip(params).collect();
+        self.scopes.push(scope);
+        let outer = self.returns.replace(ret.clone());
+        let narrowed = std::mem::take(&mut self.narrowed);
+        self.statements(&f.body);
+        if !matches!(ret, Type::Null | Type::Error) && !always_returns(&f.body) {
+            self.error(TypeErrorKind::MissingReturn(f.name.clone()), Some(ret), Type::Null, f.span);
+        }
+        self.narrowed = narrowed;
+        self.returns = outer;
+
+        let mut names = Vec::new();
+        f.body.iter().for_each(|s| assignments(s, &mut names));
+        let depth = self.scopes.len() - 1;
+        for name in names {
+            match self.binding(&name) {
+                Some(binding) if binding.0 < depth => {
+                    self.narrowed.remove(&binding);
+                    self.captured.insert(binding);
+                }
+                _ => (),
             }
         }
-        Statement::Block(stmts) => {
-            let mut t = Type::Null;
-            for stmt in stmts {
-                t = typecheck_statement(stmt, map.clone());
+        self.scopes.pop();
+    }
+
+    // check statements in order. After an `if` whose branch always returns,
+    // the rest know its condition was false
+    fn statements(&mut self, stmts: &[Statement]) -> Type {
+        let outer = self.narrowed.clone();
+        let mut t = Type::Null;
+        for stmt in stmts {
+            t = self.typecheck_statement(stmt);
+            if let StatementKind::If(e, s) = &stmt.kind {
+                if always_returns(std::slice::from_ref(s)) {
+                    let narrowings = self.narrowing(e, false);
+                    self.narrowed.extend(narrowings);
+                }
             }
-            t
         }
+        self.restore(outer);
+        t
     }
-}
 
-pub fn typecheck(ast: &Vec<Statement>, type_map: HashMap<String, String>) -> Vec<Type> {
-    let mut types = Vec::new();
-    for stmt in ast {
-        types.push(typecheck_statement(&stmt, type_map.clone()));
+    fn typecheck_statement(&mut self, stmt: &Statement) -> Type {
+        match &stmt.kind {
+            StatementKind::Expression(expr) => self.ty_of(expr),
+            StatementKind::Print(e) => self.ty_of(e),
+            StatementKind::If(e, s) => {
+                self.condition(e);
+                let narrowings = self.narrowing(e, true);
+                self.narrow(narrowings, |checker| checker.typecheck_statement(s))
+            }
+            StatementKind::IfElse(e, s1, s2) => {
+                self.condition(e);
+                // the branches can end differently (e.g. one returns) so only
+                // agreeing types carry through
+                let (yes, no) = (self.narrowing(e, true), self.narrowing(e, false));
+                let t1 = self.narrow(yes, |checker| checker.typecheck_statement(s1));
+                let t2 = self.narrow(no, |checker| checker.typecheck_statement(s2));
+                if t1 == t2 { t1 } else { Type::Null }
+            }
+            StatementKind::While(e, s) => {
+                // the body is only checked once, but a later time round
+                // sees what it assigned
+                let mut names = Vec::new();
+                expression_assignments(e, &mut names);
+                assignments(s, &mut names);
+                self.forget(names);
+                self.condition(e);
+                let narrowings = self.narrowing(e, true);
+                self.narrow(narrowings, |checker| checker.typecheck_statement(s))
+            }
+            StatementKind::Let(v, s, e) => {
+                let declared = self.annotation(s, stmt.span);
+                // checked before the name is declared, so `let x: int64 = x;`
+                // refers to an outer `x`
+                let ty = self.value(&declared, e);
+                let ty = self.expect(declared, ty, e.span);
+                self.declare(&v.name, ty.clone());
+                ty
+            }
+            StatementKind::Block(stmts) => {
+                self.scopes.push(HashMap::new());
+                let t = self.statements(stmts);
+                self.scopes.pop();
+                t
+            }
+            StatementKind::Function(_, f) => {
+                self.check_function(f);
+                Type::Null
+            }
+            StatementKind::Struct(s) => {
+                self.declare_struct(s);
+                Type::Null
+            }
+            StatementKind::Impl(name, methods) => {
+                self.check_impl(name, methods, stmt.span);
+                Type::Null
+            }
+            StatementKind::Enum(e) => {
+                self.declare_enum(e);
+                Type::Null
+            }
+            StatementKind::Return(value) => {
+                let ty = match (value, self.returns.clone()) {
+                    (Some(e), Some(expected)) => self.value(&expected, e),
+                    (Some(e), None) => self.ty_of(e),
+                    (None, _) => Type::Null,
+                };
+                let span = value.as_ref().map(|e| e.span).unwrap_or(stmt.span);
+                match self.returns.clone() {
+                    Some(expected) => self.expect(expected, ty, span),
+                    None => self.error(TypeErrorKind::ReturnOutsideFunction, None, ty, stmt.span),
+                }
+            }
+        }
     }
-    types
+
+    pub fn global(&self, name: &str) -> Option<Type> {
+        self.scopes[0].get(name).cloned()
+    }
+
+    // check `ast` in the global scope, after anything checked before
+    pub fn check(&mut self, ast: &[Statement]) -> Result<Vec<Type>, Vec<TypeError>> {
+        // every top level function is declared before anything is checked,
+        // so functions can call ones declared after them. An unknown type in
+        // a signature is reported when the function itself is checked
+        let errors = self.errors.len();
+        for stmt in ast {
+            if let StatementKind::Function(_, f) = &stmt.kind {
+                let (params, ret) = self.signature(f);
+                self.declare(&f.name, Type::Function(params, Box::new(ret)));
+            }
+        }
+        self.errors.truncate(errors);
+        let types = ast.iter().map(|stmt| self.typecheck_statement(stmt)).collect();
+        if self.errors.is_empty() {
+            Ok(types)
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+}
+
+// check every statement, returning the type of each or every error found
+pub fn typecheck(ast: &[Statement]) -> Result<Vec<Type>, Vec<TypeError>> {
+    TypeChecker::new().check(ast)
 }
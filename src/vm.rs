@@ -0,0 +1,396 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use indexmap::IndexMap;
+
+use crate::ast::Function;
+use crate::compiler::{Chunk, Op};
+use crate::environment::Environment;
+use crate::eval::*;
+use crate::tokens::Span;
+
+type Result<T> = std::result::Result<T, RuntimeError>;
+
+// calls are frames on a heap allocated vector rather than on the Rust stack,
+// so the vm can nest them far deeper than the tree-walker
+const MAX_FRAMES: usize = 1_000_000;
+
+// a chunk being executed: the top level or one function call
+struct CallFrame {
+    chunk: Rc<Chunk>,
+    ip: usize,
+    // the scope to go back to when this frame returns
+    caller: Rc<RefCell<Environment>>,
+    // the function called and the span of the call, None for the top level
+    call: Option<(Rc<Function>, Span)>,
+}
+
+// runs the bytecode from `compiler`. Behaves exactly like the tree-walking
+// `Evaluator`, including its runtime errors and their traces
+pub struct Vm {
+    env: Rc<RefCell<Environment>>,
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    structs: HashMap<String, StructType>,
+}
+
+fn unary(op: Op, operand: Value) -> std::result::Result<Value, RuntimeErrorKind> {
+    match (op, &operand) {
+        (Op::Negate, Value::Int(i)) => {
+            i.checked_neg().map(Value::Int).ok_or(RuntimeErrorKind::Overflow(op.token()))
+        }
+        (Op::Negate, Value::Float(f)) => Ok(Value::Float(-f)),
+        (Op::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+        _ => Err(RuntimeErrorKind::InvalidOperands(op.token(), vec![operand.type_name()])),
+    }
+}
+
+fn binary(op: Op, left: Value, right: Value) -> std::result::Result<Value, RuntimeErrorKind> {
+    let overflow = || RuntimeErrorKind::Overflow(op.token());
+    match (op, &left, &right) {
+        (Op::Add, Value::Int(l), Value::Int(r)) => l.checked_add(*r).map(Value::Int).ok_or_else(overflow),
+        (Op::Add, Value::Float(l), Value::Float(r)) => Ok(Value::Float(l + r)),
+        (Op::Add, Value::String(l), Value::String(r)) => Ok(Value::String(l.clone() + r)),
+        (Op::Add, Value::Array(l), Value::Array(r)) => {
+            let elements = l.borrow().iter().chain(r.borrow().iter()).cloned().collect();
+            Ok(Value::Array(Rc::new(RefCell::new(elements))))
+        }
+        (Op::Subtract, Value::Int(l), Value::Int(r)) => l.checked_sub(*r).map(Value::Int).ok_or_else(overflow),
+        (Op::Subtract, Value::Float(l), Value::Float(r)) => Ok(Value::Float(l - r)),
+        (Op::Multiply, Value::Int(l), Value::Int(r)) => l.checked_mul(*r).map(Value::Int).ok_or_else(overflow),
+        (Op::Multiply, Value::Float(l), Value::Float(r)) => Ok(Value::Float(l * r)),
+        (Op::Divide, Value::Int(_), Value::Int(0)) => Err(RuntimeErrorKind::DivisionByZero),
+        (Op::Divide, Value::Int(l), Value::Int(r)) => l.checked_div(*r).map(Value::Int).ok_or_else(overflow),
+        (Op::Divide, Value::Float(l), Value::Float(r)) => Ok(Value::Float(l / r)),
+        (Op::Equal, _, _) => Ok(Value::Bool(equal(&left, &right))),
+        (Op::NotEqual, _, _) => Ok(Value::Bool(!equal(&left, &right))),
+        (Op::Greater, Value::Int(l), Value::Int(r)) => Ok(Value::Bool(l > r)),
+        (Op::Greater, Value::Float(l), Value::Float(r)) => Ok(Value::Bool(l > r)),
+        (Op::Less, Value::Int(l), Value::Int(r)) => Ok(Value::Bool(l < r)),
+        (Op::Less, Value::Float(l), Value::Float(r)) => Ok(Value::Bool(l < r)),
+        (Op::GreaterEqual, Value::Int(l), Value::Int(r)) => Ok(Value::Bool(l >= r)),
+        (Op::GreaterEqual, Value::Float(l), Value::Float(r)) => Ok(Value::Bool(l >= r)),
+        (Op::LessEqual, Value::Int(l), Value::Int(r)) => Ok(Value::Bool(l <= r)),
+        (Op::LessEqual, Value::Float(l), Value::Float(r)) => Ok(Value::Bool(l <= r)),
+        _ => Err(RuntimeErrorKind::InvalidOperands(
+            op.token(),
+            vec![left.type_name(), right.type_name()],
+        )),
+    }
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        Vm {
+            env: Rc::new(RefCell::new(Environment::new())),
+            stack: Vec::new(),
+            frames: Vec::new(),
+            structs: HashMap::new(),
+        }
+    }
+
+    // an error at the instruction being executed, with a trace built from the
+    // constructs and calls around it
+    fn error(&self, kind: RuntimeErrorKind) -> RuntimeError {
+        let frame = self.frames.last().unwrap();
+        let mut error = RuntimeError::new(kind, frame.chunk.spans[frame.ip - 1]);
+        for frame in self.frames.iter().rev() {
+            let at = frame.ip - 1;
+            for c in frame.chunk.constructs.iter().filter(|c| c.start <= at && at < c.end) {
+                error = error.within(c.name, c.span);
+            }
+            if let Some((function, span)) = &frame.call {
+                error = error.within(&format!("function '{}'", function.name), *span);
+            }
+        }
+        error
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("vm stack underflow")
+    }
+
+    fn call(&mut self, argc: usize) -> Result<()> {
+        let base = self.stack.len() - argc - 1;
+        let closure = match &self.stack[base] {
+            Value::Function(closure) => closure.clone(),
+            other => return Err(self.error(RuntimeErrorKind::NotCallable(other.type_name()))),
+        };
+        let f = &closure.function;
+        if f.params.len() != argc {
+            return Err(self.error(RuntimeErrorKind::WrongArgumentCount(f.params.len(), argc)));
+        }
+        // the top level frame is not a call
+        if self.frames.len() > MAX_FRAMES {
+            return Err(self.error(RuntimeErrorKind::StackOverflow(MAX_FRAMES)));
+        }
+
+        let mut scope = Environment::new_from(closure.env.clone());
+        for (index, arg) in self.stack.drain(base + 1..).enumerate() {
+            scope.define(index, arg);
+        }
+        self.stack.pop();
+
+        let frame = self.frames.last().unwrap();
+        let span = frame.chunk.spans[frame.ip - 1];
+        let caller = std::mem::replace(&mut self.env, Rc::new(RefCell::new(scope)));
+        self.frames.push(CallFrame {
+            chunk: closure.chunk.clone().expect("closure was not compiled"),
+            ip: 0,
+            caller,
+            call: Some((closure.function.clone(), span)),
+        });
+        Ok(())
+    }
+
+    fn execute(&mut self) -> Result<()> {
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            let op = frame.chunk.code[frame.ip];
+            frame.ip += 1;
+
+            match op {
+                Op::Constant(index) => {
+                    let value = frame.chunk.constants[index].clone();
+                    self.stack.push(value);
+                }
+                Op::Null => self.stack.push(Value::Null),
+                Op::Pop => {
+                    self.pop();
+                }
+                // a slot is only missing when a top level function reads a
+                // global before its `let` has run
+                Op::Get(slot, name) => match self.env.borrow().get(slot) {
+                    Some(value) => self.stack.push(value),
+                    None => {
+                        let name = frame.chunk.constants[name].to_string();
+                        return Err(self.error(RuntimeErrorKind::UndefinedVariable(name)));
+                    }
+                },
+                Op::Set(slot, name) => {
+                    let value = self.stack.last().unwrap().clone();
+                    if self.env.borrow_mut().assign(slot, value).is_err() {
+                        let name = frame.chunk.constants[name].to_string();
+                        return Err(self.error(RuntimeErrorKind::UndefinedVariable(name)));
+                    }
+                }
+                Op::Define(index) => {
+                    let value = self.pop();
+                    self.env.borrow_mut().define(index, value);
+                }
+                Op::Undefined(index) => {
+                    let name = frame.chunk.constants[index].to_string();
+                    return Err(self.error(RuntimeErrorKind::UndefinedVariable(name)));
+                }
+                Op::Negate | Op::Not => {
+                    let operand = self.pop();
+                    match unary(op, operand) {
+                        Ok(value) => self.stack.push(value),
+                        Err(kind) => return Err(self.error(kind)),
+                    }
+                }
+                Op::Add | Op::Subtract | Op::Multiply | Op::Divide | Op::Equal | Op::NotEqual |
+                    Op::Greater | Op::Less | Op::GreaterEqual | Op::LessEqual => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    match binary(op, left, right) {
+                        Ok(value) => self.stack.push(value),
+                        Err(kind) => return Err(self.error(kind)),
+                    }
+                }
+                Op::Interpolate(count) => {
+                    let values = self.stack.split_off(self.stack.len() - count);
+                    let s: String = values.iter().map(|v| v.to_string()).collect();
+                    self.stack.push(Value::String(s));
+                }
+                Op::Array(count) => {
+                    let elements = self.stack.split_off(self.stack.len() - count);
+                    self.stack.push(Value::Array(Rc::new(RefCell::new(elements))));
+                }
+                Op::Map(count) => {
+                    let values = self.stack.split_off(self.stack.len() - 2 * count);
+                    let map = Value::Map(Rc::new(RefCell::new(IndexMap::new())));
+                    for pair in values.chunks(2) {
+                        if let Err(kind) = set_index(&map, &pair[0], pair[1].clone()) {
+                            return Err(self.error(kind));
+                        }
+                    }
+                    self.stack.push(map);
+                }
+                Op::Index => {
+                    let i = self.pop();
+                    let target = self.pop();
+                    match index(&target, &i) {
+                        Ok(value) => self.stack.push(value),
+                        Err(kind) => return Err(self.error(kind)),
+                    }
+                }
+                Op::SetIndex => {
+                    let value = self.pop();
+                    let i = self.pop();
+                    let target = self.pop();
+                    if let Err(kind) = set_index(&target, &i, value.clone()) {
+                        return Err(self.error(kind));
+                    }
+                    self.stack.push(value);
+                }
+                Op::Slice => {
+                    let end = self.pop();
+                    let start = self.pop();
+                    let target = self.pop();
+                    match slice(&target, &start, &end) {
+                        Ok(value) => self.stack.push(value),
+                        Err(kind) => return Err(self.error(kind)),
+                    }
+                }
+                Op::Method(name, argc) => {
+                    let name = frame.chunk.constants[name].to_string();
+                    // a struct method is called with the receiver as `self`
+                    let base = self.stack.len() - argc - 1;
+                    if let Some(closure) = struct_method(&self.structs, &self.stack[base], &name) {
+                        self.stack.insert(base, Value::Function(closure));
+                        self.call(argc + 1)?;
+                        continue;
+                    }
+                    let args = self.stack.split_off(self.stack.len() - argc);
+                    let receiver = self.pop();
+                    match call_method(&receiver, &name, args) {
+                        Ok(value) => self.stack.push(value),
+                        Err(kind) => return Err(self.error(kind)),
+                    }
+                }
+                Op::Struct(index) => {
+                    let declaration = frame.chunk.structs[index].clone();
+                    self.structs.insert(declaration.name.clone(), StructType::new(declaration));
+                }
+                Op::DefineMethod(name) => {
+                    let name = frame.chunk.constants[name].to_string();
+                    if let Value::Function(closure) = self.pop() {
+                        if let Some(ty) = self.structs.get_mut(&name) {
+                            ty.methods.insert(closure.function.name.clone(), closure);
+                        }
+                    }
+                }
+                Op::Instance(name, count) => {
+                    let name = frame.chunk.constants[name].to_string();
+                    let values = self.stack.split_off(self.stack.len() - 2 * count);
+                    let fields = values.chunks(2).map(|pair| (pair[0].to_string(), pair[1].clone())).collect();
+                    match instance(&self.structs, &name, fields) {
+                        Ok(value) => self.stack.push(value),
+                        Err(kind) => return Err(self.error(kind)),
+                    }
+                }
+                Op::GetField(name) => {
+                    let name = frame.chunk.constants[name].to_string();
+                    let target = self.pop();
+                    match field(&target, &name) {
+                        Ok(value) => self.stack.push(value),
+                        Err(kind) => return Err(self.error(kind)),
+                    }
+                }
+                Op::SetField(name) => {
+                    let name = frame.chunk.constants[name].to_string();
+                    let value = self.pop();
+                    let target = self.pop();
+                    if let Err(kind) = set_field(&target, &name, value.clone()) {
+                        return Err(self.error(kind));
+                    }
+                    self.stack.push(value);
+                }
+                Op::Variant(name, v, count) => {
+                    let name = frame.chunk.constants[name].to_string();
+                    let v = frame.chunk.constants[v].to_string();
+                    let fields = self.stack.split_off(self.stack.len() - count);
+                    self.stack.push(variant(&name, &v, fields));
+                }
+                Op::Match(pattern) => {
+                    let mut bindings = Vec::new();
+                    let matched = matches(&frame.chunk.patterns[pattern], self.stack.last().unwrap(), &mut bindings);
+                    for (index, value) in bindings {
+                        self.env.borrow_mut().define(index, value);
+                    }
+                    self.stack.push(Value::Bool(matched));
+                }
+                Op::NoMatch => {
+                    let value = self.pop();
+                    return Err(self.error(no_match(&value)));
+                }
+                Op::Jump(target) => frame.ip = target,
+                Op::JumpIfFalse(target) => {
+                    if !self.stack.pop().expect("vm stack underflow").is_truthy() {
+                        frame.ip = target;
+                    }
+                }
+                Op::JumpIfFalseOrPop(target) => {
+                    if *self.stack.last().expect("vm stack underflow") == Value::Bool(false) {
+                        frame.ip = target;
+                    } else {
+                        self.pop();
+                    }
+                }
+                Op::JumpIfTrueOrPop(target) => {
+                    if *self.stack.last().expect("vm stack underflow") == Value::Bool(true) {
+                        frame.ip = target;
+                    } else {
+                        self.pop();
+                    }
+                }
+                Op::JumpIfNull(target) => {
+                    if *self.stack.last().expect("vm stack underflow") == Value::Null {
+                        frame.ip = target;
+                    }
+                }
+                Op::EnterScope => {
+                    let scope = Environment::new_from(self.env.clone());
+                    self.env = Rc::new(RefCell::new(scope));
+                }
+                Op::ExitScope => {
+                    let outer = self.env.borrow().enclosing().expect("exited the global scope");
+                    self.env = outer;
+                }
+                Op::Closure(index) => {
+                    let (function, chunk) = &frame.chunk.functions[index];
+                    let closure = Closure {
+                        function: function.clone(),
+                        env: self.env.clone(),
+                        chunk: Some(chunk.clone()),
+                    };
+                    self.stack.push(Value::Function(closure));
+                }
+                Op::Call(argc) => self.call(argc)?,
+                Op::Return => {
+                    let value = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.env = frame.caller;
+                    if self.frames.is_empty() {
+                        return Ok(());
+                    }
+                    self.stack.push(value);
+                }
+                Op::Print => {
+                    let value = self.pop();
+                    println!("{}", value);
+                }
+            }
+        }
+    }
+
+    // run a compiled program in this vm's global scope
+    pub fn run(&mut self, chunk: Chunk) -> Result<()> {
+        self.frames.push(CallFrame {
+            chunk: Rc::new(chunk),
+            ip: 0,
+            caller: self.env.clone(),
+            call: None,
+        });
+        let result = self.execute();
+        if result.is_err() {
+            // unwind to the global scope so the vm can be used again
+            self.env = self.frames[0].caller.clone();
+            self.frames.clear();
+            self.stack.clear();
+        }
+        result
+    }
+}
@@ -0,0 +1,40 @@
+// the vm gives the same results as the tree-walker on every example
+
+mod common;
+
+use std::fs;
+
+use common::mt;
+
+// loops forever, on purpose
+const ENDLESS: &[&str] = &["while.mt"];
+
+#[test]
+fn examples_run_the_same_on_both_backends() {
+    let mut examples: Vec<_> = fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/examples"))
+        .expect("cannot read examples")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "mt"))
+        .filter(|path| !ENDLESS.iter().any(|name| path.ends_with(name)))
+        .collect();
+    examples.sort();
+    assert!(!examples.is_empty());
+
+    for path in examples {
+        let path = path.to_str().unwrap();
+        let walked = mt(&[path]);
+        let vm = mt(&["--vm", path]);
+        assert_eq!(walked.code, vm.code, "{}: exit codes differ", path);
+        assert_eq!(walked.stdout, vm.stdout, "{}: output differs", path);
+    }
+}
+
+#[test]
+fn reading_a_global_before_its_let_fails_on_both_backends() {
+    let code = "print f(); let y: int64 = 1; fn f() -> int64 { return y; }";
+    for args in [vec!["-e", code], vec!["--vm", "-e", code]] {
+        let run = mt(&args);
+        assert_eq!(run.code, 1);
+        assert!(run.stderr.contains("E0300"), "{}", run.stderr);
+    }
+}
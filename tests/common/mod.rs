@@ -0,0 +1,29 @@
+use std::process::Command;
+
+// what a run of the mt binary produced
+pub struct Run {
+    pub code: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+// run the mt binary with `args`
+pub fn mt(args: &[&str]) -> Run {
+    let output = Command::new(env!("CARGO_BIN_EXE_mt")).args(args).output().expect("cannot run mt");
+    Run {
+        code: output.status.code().expect("mt was killed by a signal"),
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+    }
+}
+
+// run `code` on the tree-walker and on the vm, check both print the same and
+// return what the tree-walker printed
+#[allow(dead_code)]
+pub fn run_both(code: &str) -> String {
+    let walked = mt(&["-e", code]);
+    let vm = mt(&["--vm", "-e", code]);
+    assert_eq!(walked.code, 0, "{}", walked.stderr);
+    assert_eq!((walked.code, &walked.stdout), (vm.code, &vm.stdout), "{}", vm.stderr);
+    walked.stdout
+}
@@ -0,0 +1,33 @@
+mod common;
+
+use common::{mt, run_both};
+
+#[test]
+fn and_skips_the_right_when_the_left_is_false() {
+    let code = "fn loud() -> bool { print \"ran\"; return true; } print false && loud(); print true && loud();";
+    assert_eq!(run_both(code), "false\nran\ntrue\n");
+}
+
+#[test]
+fn or_skips_the_right_when_the_left_is_true() {
+    let code = "fn loud() -> bool { print \"ran\"; return false; } print true || loud(); print false || loud();";
+    assert_eq!(run_both(code), "true\nran\nfalse\n");
+}
+
+#[test]
+fn right_of_and_sees_the_left_is_not_null() {
+    let code = "let x: int64? = 5; if (x != null && x > 0) { print x; } let y: int64? = null; print y != null && y > 0;";
+    assert_eq!(run_both(code), "5\nfalse\n");
+}
+
+#[test]
+fn right_of_or_sees_the_left_is_not_null() {
+    let code = "let x: int64? = null; print x == null || x > 0; x = -1; print x == null || x > 0;";
+    assert_eq!(run_both(code), "true\nfalse\n");
+}
+
+#[test]
+fn right_of_or_is_not_narrowed_by_a_null_check_that_passed() {
+    let run = mt(&["-e", "let x: int64? = 5; print x != null || x > 0;"]);
+    assert_eq!(run.code, 4);
+}
@@ -0,0 +1,84 @@
+// blocks open a scope at runtime and in the typechecker. A `let` in a block
+// ends with it, and may shadow an outer variable with a different type
+
+mod common;
+
+use common::{mt, run_both};
+
+#[test]
+fn let_in_block_does_not_leak() {
+    let run = mt(&["-e", "{ let x: int64 = 1; } print x;"]);
+    assert_eq!(run.code, 4);
+    assert!(run.stderr.contains("E0400"), "{}", run.stderr);
+
+    let run = mt(&["-e", "if (true) { let y: int64 = 1; } print y;"]);
+    assert_eq!(run.code, 4);
+    assert!(run.stderr.contains("E0400"), "{}", run.stderr);
+}
+
+#[test]
+fn inner_let_shadows_with_another_type() {
+    let code = r#"let x: int64 = 1; { let x: string = "inner"; print x; print x + "!"; }"#;
+    assert_eq!(run_both(code), "inner\ninner!\n");
+}
+
+#[test]
+fn outer_value_comes_back_after_block() {
+    let code = r#"
+        let x: int64 = 1;
+        {
+            let x: string = "inner";
+            x = "changed";
+        }
+        print x + 1;
+    "#;
+    assert_eq!(run_both(code), "2\n");
+}
+
+#[test]
+fn outer_type_comes_back_after_block() {
+    let run = mt(&["check", "-e", r#"let x: int64 = 1; { let x: string = "a"; } let s: string = x;"#]);
+    assert_eq!(run.code, 4);
+    assert!(run.stderr.contains("E0200"), "{}", run.stderr);
+    assert!(run.stderr.contains("expected string, found int64"), "{}", run.stderr);
+}
+
+#[test]
+fn assignment_in_block_reaches_outer_variable() {
+    assert_eq!(run_both("let x: int64 = 1; { x = 2; } print x;"), "2\n");
+}
+
+#[test]
+fn closure_keeps_the_binding_it_captured() {
+    let code = r#"
+        let x: int64 = 1;
+        let f: fn() -> int64 = fn() -> int64 { return 0; };
+        {
+            let x: int64 = 10;
+            f = fn() -> int64 { return x; };
+        }
+        print f() + x;
+    "#;
+    assert_eq!(run_both(code), "11\n");
+}
+
+#[test]
+fn top_level_functions_can_call_each_other() {
+    let code = r#"
+        print even(10);
+        fn even(n: int64) -> bool {
+            if (n == 0) {
+                return true;
+            }
+            return odd(n - 1);
+        }
+        fn odd(n: int64) -> bool {
+            if (n == 0) {
+                return false;
+            }
+            return even(n - 1);
+        }
+        print odd(7);
+    "#;
+    assert_eq!(run_both(code), "true\ntrue\n");
+}